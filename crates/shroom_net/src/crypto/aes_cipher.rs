@@ -2,10 +2,9 @@ use std::ops::Add;
 
 use aes::Aes256;
 use cipher::{
-    block_padding::NoPadding,
     generic_array::GenericArray,
     inout::InOutBuf,
-    typenum::{U1000, U16, U460},
+    typenum::{U1000, U460},
     BlockEncrypt, KeyInit,
 };
 
@@ -15,9 +14,58 @@ use super::{RoundKey, AES_BLOCK_LEN, DEFAULT_AES_KEY};
 
 const BLOCK_LEN: usize = 1460;
 const FIRST_BLOCK_LEN: usize = BLOCK_LEN - 4;
+// Keystream is generated in full AES blocks
+const KEYSTREAM_LEN: usize = BLOCK_LEN.next_multiple_of(AES_BLOCK_LEN);
 type U1460 = <U1000 as Add<U460>>::Output;
 
-pub struct ShroomAESCipher(Aes256);
+/// OFB keystream for a round key, the keystream is the same for every chunk
+/// so It's only generated once and extended on demand up to the chunk size
+struct Keystream {
+    key: RoundKey,
+    len: usize,
+    data: [u8; KEYSTREAM_LEN],
+}
+
+impl Keystream {
+    fn new(key: RoundKey) -> Self {
+        Self {
+            key,
+            len: 0,
+            data: [0; KEYSTREAM_LEN],
+        }
+    }
+
+    /// Resets the keystream If the key changed
+    fn reset(&mut self, key: RoundKey) {
+        if self.key != key {
+            self.key = key;
+            self.len = 0;
+        }
+    }
+
+    /// Extends the keystream to at least `n` bytes
+    fn extend(&mut self, aes: &Aes256, n: usize) {
+        let n = n.min(BLOCK_LEN);
+        // OFB chains every block to the previous one,
+        // so the blocks have to be generated sequentially
+        let mut block = if self.len == 0 {
+            self.key.expand()
+        } else {
+            *GenericArray::from_slice(&self.data[self.len - AES_BLOCK_LEN..self.len])
+        };
+
+        while self.len < n {
+            aes.encrypt_block(&mut block);
+            self.data[self.len..self.len + AES_BLOCK_LEN].copy_from_slice(&block);
+            self.len += AES_BLOCK_LEN;
+        }
+    }
+}
+
+pub struct ShroomAESCipher {
+    aes: Aes256,
+    keystream: Keystream,
+}
 
 impl Default for ShroomAESCipher {
     fn default() -> Self {
@@ -27,56 +75,53 @@ impl Default for ShroomAESCipher {
 
 impl ShroomAESCipher {
     pub fn new(key: &[u8]) -> NetResult<Self> {
-        Ok(Self(
-            Aes256::new_from_slice(key).map_err(|_| NetError::InvalidAESKey)?,
-        ))
+        Ok(Self {
+            aes: Aes256::new_from_slice(key).map_err(|_| NetError::InvalidAESKey)?,
+            keystream: Keystream::new(RoundKey::zero()),
+        })
     }
 
-    fn get_next_key(&self, key: &mut GenericArray<u8, U16>) {
-        self.0
-            .encrypt_padded::<NoPadding>(key, AES_BLOCK_LEN)
-            .unwrap();
+    /// Gets the keystream for the key with at least `n` bytes
+    fn keystream(&mut self, key: RoundKey, n: usize) -> &[u8] {
+        self.keystream.reset(key);
+        self.keystream.extend(&self.aes, n);
+        &self.keystream.data[..self.keystream.len]
     }
 
-    fn crypt_block(&self, mut key: GenericArray<u8, U16>, buf: InOutBuf<'_, '_, u8>) {
-        let (aes_blocks, mut aes_tail) = buf.into_chunks::<U16>();
-        for mut aes_block in aes_blocks {
-            self.get_next_key(&mut key);
-            aes_block.xor_in2out(&key);
-        }
-
-        self.get_next_key(&mut key);
-        aes_tail.xor_in2out(&key[..aes_tail.len()]);
-    }
-
-    pub fn crypt(&self, key: RoundKey, buf: InOutBuf<'_, '_, u8>) {
-        //Expands the 4 byte round key to a 16 byte IV, this IV is re-used for every chunk
-        let iv = key.expand();
+    pub fn crypt(&mut self, key: RoundKey, buf: InOutBuf<'_, '_, u8>) {
+        // The 4 byte round key is expanded to a 16 byte IV, this IV is re-used for every chunk
+        // so every chunk is xored with the same keystream
         let n = buf.len();
+        let keystream = self.keystream(key, n);
 
         // Crypt first block
-        // Need proper benchmarking for that
-        let (first_chunk, buf) = buf.split_at(FIRST_BLOCK_LEN.min(n));
-        self.crypt_block(iv, first_chunk);
+        let (mut first_chunk, buf) = buf.split_at(FIRST_BLOCK_LEN.min(n));
+        first_chunk.xor_in2out(&keystream[..first_chunk.len()]);
 
         // Crypt all middle blocks
-        let (blocks, tail_block) = buf.into_chunks::<U1460>();
-        for mut block in blocks {
-            self.crypt_block(iv, block.get_out().as_mut().into());
+        let (blocks, mut tail_block) = buf.into_chunks::<U1460>();
+        if !blocks.is_empty() {
+            let block_keystream = GenericArray::from_slice(&keystream[..BLOCK_LEN]);
+            for mut block in blocks {
+                block.xor_in2out(block_keystream);
+            }
         }
 
         // Crypt tail block
-        self.crypt_block(iv, tail_block);
+        tail_block.xor_in2out(&keystream[..tail_block.len()]);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::RoundKey;
+    use aes::Aes256;
+    use cipher::{BlockEncrypt, KeyInit};
+
+    use crate::crypto::{RoundKey, DEFAULT_AES_KEY};
 
-    use super::ShroomAESCipher;
+    use super::{ShroomAESCipher, BLOCK_LEN, FIRST_BLOCK_LEN};
 
-    fn enc_dec(cipher: &ShroomAESCipher, key: RoundKey, data: &mut [u8]) {
+    fn enc_dec(cipher: &mut ShroomAESCipher, key: RoundKey, data: &mut [u8]) {
         cipher.crypt(key, data.into());
         cipher.crypt(key, data.into());
     }
@@ -85,21 +130,57 @@ mod tests {
 
     #[test]
     fn en_dec_aes() {
-        let aes = ShroomAESCipher::default();
+        let mut aes = ShroomAESCipher::default();
         let data = b"abcdef";
 
         let mut data_enc = *data;
-        enc_dec(&aes, KEY, data_enc.as_mut());
+        enc_dec(&mut aes, KEY, data_enc.as_mut());
         assert_eq!(*data, data_enc);
     }
 
     #[test]
     fn en_dec_aes_large() {
-        let aes = ShroomAESCipher::default();
+        let mut aes = ShroomAESCipher::default();
         let data = (0..=255).cycle().take(4096).collect::<Vec<u8>>();
 
         let mut data_enc: Vec<u8> = data.clone();
-        enc_dec(&aes, KEY, data_enc.as_mut_slice());
+        enc_dec(&mut aes, KEY, data_enc.as_mut_slice());
         assert_eq!(data, data_enc);
     }
+
+    // Reference implementation, which re-generates the keystream block by block for every chunk
+    fn crypt_ref(key: RoundKey, data: &mut [u8]) {
+        let aes = Aes256::new_from_slice(DEFAULT_AES_KEY).unwrap();
+        let (first, rest) = data.split_at_mut(FIRST_BLOCK_LEN.min(data.len()));
+        for chunk in std::iter::once(first).chain(rest.chunks_mut(BLOCK_LEN)) {
+            let mut iv = key.expand();
+            for aes_block in chunk.chunks_mut(16) {
+                aes.encrypt_block(&mut iv);
+                aes_block
+                    .iter_mut()
+                    .zip(iv.iter())
+                    .for_each(|(b, k)| *b ^= k);
+            }
+        }
+    }
+
+    #[test]
+    fn keystream_matches_ref() {
+        let mut aes = ShroomAESCipher::default();
+        let keys = [KEY, RoundKey([5, 6, 7, 8]), KEY];
+        for (i, n) in [0, 1, 16, 17, 1455, 1456, 1457, 2916, 2917, 5000, 3]
+            .into_iter()
+            .enumerate()
+        {
+            let key = keys[i % keys.len()];
+            let data = (0..=255).cycle().take(n).collect::<Vec<u8>>();
+
+            let mut expected = data.clone();
+            crypt_ref(key, &mut expected);
+
+            let mut actual = data.clone();
+            aes.crypt(key, actual.as_mut_slice().into());
+            assert_eq!(actual, expected, "len: {n}");
+        }
+    }
 }
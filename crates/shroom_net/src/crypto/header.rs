@@ -1,6 +1,6 @@
 use crate::{NetError, NetResult};
 
-use super::{PacketHeader, RoundKey, PACKET_HEADER_LEN};
+use super::{ExtendedLenHeader, PacketHeader, RoundKey, PACKET_HEADER_LEN};
 
 /// Small helper to work with high low words in a 32 bit integer
 struct HiLo32 {
@@ -53,12 +53,23 @@ pub fn encode_header(key: RoundKey, length: u16, ver: u16) -> PacketHeader {
     hilo.to_le_bytes()
 }
 
+/// Encodes the extended length, which follows the header
+/// when the header length is set to the `EXTENDED_LEN_MARKER`
+pub fn encode_extended_len(key: RoundKey, length: u32) -> ExtendedLenHeader {
+    (length ^ u32::from(key)).to_le_bytes()
+}
+
+/// Decodes the extended length
+pub fn decode_extended_len(hdr: ExtendedLenHeader, key: RoundKey) -> u32 {
+    u32::from_le_bytes(hdr) ^ u32::from(key)
+}
+
 #[cfg(test)]
 mod tests {
 
     use crate::crypto::RoundKey;
 
-    use super::{decode_header, encode_header};
+    use super::{decode_extended_len, decode_header, encode_extended_len, encode_header};
 
     const KEY: RoundKey = RoundKey([82, 48, 120, 232]);
     const KEY2: RoundKey = RoundKey([82, 48, 120, 89]);
@@ -77,4 +88,12 @@ mod tests {
             assert_eq!(decode_header(a, key, ver).expect("valid header"), ln)
         }
     }
+
+    #[test]
+    fn extended_len_enc_dec() {
+        for ln in [0, u16::MAX as u32, 1 << 20, u32::MAX] {
+            let a = encode_extended_len(KEY, ln);
+            assert_eq!(decode_extended_len(a, KEY), ln);
+        }
+    }
 }
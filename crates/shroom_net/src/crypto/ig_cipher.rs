@@ -20,10 +20,7 @@ pub const DEFAULT_IG_CONTEXT: IgContext = IgContext {
 impl IgContext {
     /// Creates a new IgContext
     pub fn new(shuffle_key: ShuffleKey, seed: IgKey) -> Self {
-        Self {
-            shuffle_key,
-            seed
-        }
+        Self { shuffle_key, seed }
     }
 
     /// Creates a new hasher with this context
@@ -143,7 +140,6 @@ impl<'ctx> IgCipher<'ctx> {
 mod tests {
     use super::DEFAULT_IG_CONTEXT;
 
-
     #[test]
     fn ig_dec_enc() {
         let data: &[&[u8]] = &[&[1u8, 2], &[], &[1]];
@@ -2,19 +2,23 @@ pub mod aes_cipher;
 mod default_keys;
 pub mod header;
 pub mod ig_cipher;
+pub mod null_cipher;
+pub mod profile;
 mod round_key;
 pub mod shanda_cipher;
 
 // Re-exports
 pub use default_keys::{DEFAULT_AES_KEY, DEFAULT_INIT_IG_SEED, DEFAULT_SHUFFLE_KEY};
-pub use round_key::RoundKey;
 pub use ig_cipher::IgCipher;
+pub use null_cipher::NullCipher;
+pub use profile::{AesVariant, CryptoProfile, HeaderScheme};
+pub use round_key::RoundKey;
 
 use std::sync::Arc;
 
 use cipher::inout::InOutBuf;
 
-use crate::NetResult;
+use crate::{net::codec::handshake::LocaleCode, NetResult};
 
 use self::{
     aes_cipher::ShroomAESCipher,
@@ -26,10 +30,14 @@ pub const ROUND_KEY_LEN: usize = 4;
 pub const AES_KEY_LEN: usize = 32;
 pub const AES_BLOCK_LEN: usize = 16;
 pub const PACKET_HEADER_LEN: usize = 4;
+pub const EXTENDED_LEN_HEADER_LEN: usize = 4;
+/// Header length which signals an extended length follows the header
+pub const EXTENDED_LEN_MARKER: u16 = u16::MAX;
 
 pub type AesKey = [u8; AES_KEY_LEN];
 pub type ShuffleKey = [u8; 256];
 pub type PacketHeader = [u8; PACKET_HEADER_LEN];
+pub type ExtendedLenHeader = [u8; EXTENDED_LEN_HEADER_LEN];
 
 pub type SharedIgContext = Arc<IgContext>;
 
@@ -50,6 +58,13 @@ impl ShroomVersion {
 pub struct CryptoContext {
     pub aes_key: AesKey,
     pub ig_ctx: IgContext,
+    /// Fixed profile, If not set the profile is picked based on the handshake version
+    pub profile: Option<CryptoProfile>,
+    /// Use the `NullCipher` for sessions, which keeps the payloads in plaintext
+    /// Only meant for internal links and debugging
+    pub plaintext: bool,
+    /// Overrides the max frame length of the header scheme
+    pub max_frame_len: Option<usize>,
 }
 
 impl Default for CryptoContext {
@@ -57,38 +72,126 @@ impl Default for CryptoContext {
         Self {
             aes_key: *DEFAULT_AES_KEY,
             ig_ctx: DEFAULT_IG_CONTEXT,
+            profile: None,
+            plaintext: false,
+            max_frame_len: None,
         }
     }
 }
 
+impl CryptoContext {
+    /// Gets the profile for the given major version and locale
+    pub fn profile_for(&self, version: ShroomVersion, locale: LocaleCode) -> CryptoProfile {
+        self.profile
+            .unwrap_or_else(|| CryptoProfile::from_version(version, locale))
+    }
+}
+
 /// Alias for a shared context
 pub type SharedCryptoContext = Arc<CryptoContext>;
 
+/// Cipher which is used to frame and crypt packets
+pub trait PacketCipher {
+    /// Encodes the header for a packet with the given length
+    fn encode_header(&self, length: u16) -> PacketHeader;
+
+    /// Decodes and verifies a header, returns the length of the packet
+    fn decode_header(&self, hdr: PacketHeader) -> NetResult<u16>;
+
+    /// Encodes the extended length, which follows a header with the `EXTENDED_LEN_MARKER`
+    fn encode_extended_len(&self, length: u32) -> ExtendedLenHeader;
+
+    /// Decodes the extended length
+    fn decode_extended_len(&self, hdr: ExtendedLenHeader) -> u32;
+
+    /// Encrypts the payload of one packet
+    fn encrypt(&mut self, data: InOutBuf<u8>);
+
+    /// Decrypts the payload of one packet
+    fn decrypt(&mut self, data: InOutBuf<u8>);
+
+    /// Crypto profile of the cipher, If It's based on one
+    fn profile(&self) -> Option<CryptoProfile> {
+        None
+    }
+}
+
+/// Boxed cipher, used by the codec to select the cipher at runtime
+pub type BoxedPacketCipher = Box<dyn PacketCipher + Send>;
+
+impl<C: PacketCipher + ?Sized> PacketCipher for Box<C> {
+    fn encode_header(&self, length: u16) -> PacketHeader {
+        (**self).encode_header(length)
+    }
+
+    fn decode_header(&self, hdr: PacketHeader) -> NetResult<u16> {
+        (**self).decode_header(hdr)
+    }
+
+    fn encode_extended_len(&self, length: u32) -> ExtendedLenHeader {
+        (**self).encode_extended_len(length)
+    }
+
+    fn decode_extended_len(&self, hdr: ExtendedLenHeader) -> u32 {
+        (**self).decode_extended_len(hdr)
+    }
+
+    fn encrypt(&mut self, data: InOutBuf<u8>) {
+        (**self).encrypt(data)
+    }
+
+    fn decrypt(&mut self, data: InOutBuf<u8>) {
+        (**self).decrypt(data)
+    }
+
+    fn profile(&self) -> Option<CryptoProfile> {
+        (**self).profile()
+    }
+}
+
 pub struct ShroomCrypto {
     shroom_aes_cipher: ShroomAESCipher,
     ctx: SharedCryptoContext,
     round_key: RoundKey,
     version: ShroomVersion,
+    profile: CryptoProfile,
 }
 
 impl ShroomCrypto {
     /// Creates a new crypto used en/decoding packets
     /// with the given context, initial `RoundKey`and version
     pub fn new(ctx: SharedCryptoContext, round_key: RoundKey, version: ShroomVersion) -> Self {
+        Self::with_profile(ctx, round_key, version, CryptoProfile::default())
+    }
+
+    /// Creates a new crypto which applies the layers of the given profile
+    pub fn with_profile(
+        ctx: SharedCryptoContext,
+        round_key: RoundKey,
+        version: ShroomVersion,
+        profile: CryptoProfile,
+    ) -> Self {
         Self {
             shroom_aes_cipher: ShroomAESCipher::new(&ctx.aes_key).unwrap(),
             round_key,
             ctx,
             version,
+            profile,
         }
     }
 
+    /// Gets the profile of this crypto
+    pub fn profile(&self) -> CryptoProfile {
+        self.profile
+    }
+
     /// Updates the current round key
     fn update_round_key(&mut self) {
         self.round_key = self.round_key.update(&self.ctx.ig_ctx);
     }
 
-    /// Decodes and verifies a header from the given bytes
+    /// Encodes the header for a packet with the given length,
+    /// both header schemes share the header, the extended length is framed by the codec
     pub fn encode_header(&self, length: u16) -> PacketHeader {
         header::encode_header(self.round_key, length, self.version.0)
     }
@@ -98,27 +201,78 @@ impl ShroomCrypto {
         header::decode_header(hdr, self.round_key, self.version.0)
     }
 
+    /// Encodes the extended length
+    pub fn encode_extended_len(&self, length: u32) -> ExtendedLenHeader {
+        header::encode_extended_len(self.round_key, length)
+    }
+
+    /// Decodes the extended length
+    pub fn decode_extended_len(&self, hdr: ExtendedLenHeader) -> u32 {
+        header::decode_extended_len(hdr, self.round_key)
+    }
+
+    /// Applies the AES layer of the profile
+    fn crypt_aes(&mut self, data: InOutBuf<u8>) {
+        match self.profile.aes {
+            AesVariant::Ofb => self.shroom_aes_cipher.crypt(self.round_key, data),
+            AesVariant::Disabled => (),
+        }
+    }
+
     /// Decrypt a chunk of data
     /// IMPORTANT: only call this with a full block of data, because the internal state updates
     pub fn encrypt(&mut self, mut data: InOutBuf<u8>) {
-        ShandaCipher::encrypt(data.reborrow());
-        self.shroom_aes_cipher.crypt(self.round_key, data);
+        if self.profile.shanda {
+            ShandaCipher::encrypt(data.reborrow());
+        }
+        self.crypt_aes(data);
         self.update_round_key();
     }
 
     /// Encrypts a chunk of data
     /// IMPORTANT: only call this with a full block of data, because the internal state updates
     pub fn decrypt(&mut self, mut data: InOutBuf<u8>) {
-        self.shroom_aes_cipher
-            .crypt(self.round_key, data.reborrow());
+        self.crypt_aes(data.reborrow());
         self.update_round_key();
-        ShandaCipher::decrypt(data);
+        if self.profile.shanda {
+            ShandaCipher::decrypt(data);
+        }
+    }
+}
+
+impl PacketCipher for ShroomCrypto {
+    fn encode_header(&self, length: u16) -> PacketHeader {
+        self.encode_header(length)
+    }
+
+    fn decode_header(&self, hdr: PacketHeader) -> NetResult<u16> {
+        self.decode_header(hdr)
+    }
+
+    fn encode_extended_len(&self, length: u32) -> ExtendedLenHeader {
+        self.encode_extended_len(length)
+    }
+
+    fn decode_extended_len(&self, hdr: ExtendedLenHeader) -> u32 {
+        self.decode_extended_len(hdr)
+    }
+
+    fn encrypt(&mut self, data: InOutBuf<u8>) {
+        self.encrypt(data)
+    }
+
+    fn decrypt(&mut self, data: InOutBuf<u8>) {
+        self.decrypt(data)
+    }
+
+    fn profile(&self) -> Option<CryptoProfile> {
+        Some(self.profile)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::{RoundKey, ShroomCrypto};
+    use crate::crypto::{aes_cipher::ShroomAESCipher, CryptoProfile, RoundKey, ShroomCrypto};
 
     use super::{SharedCryptoContext, ShroomVersion};
     const V: ShroomVersion = ShroomVersion(95);
@@ -161,4 +315,26 @@ mod tests {
             assert_eq!(*data, data_enc);
         }
     }
+
+    #[test]
+    fn en_dec_aes_only() {
+        let key = RoundKey([1, 2, 3, 4]);
+        let ctx = SharedCryptoContext::default();
+
+        let mut enc = ShroomCrypto::with_profile(ctx.clone(), key, V, CryptoProfile::AES_ONLY);
+        let mut dec = ShroomCrypto::with_profile(ctx, key, V, CryptoProfile::AES_ONLY);
+        let data = b"abcdef";
+
+        let mut data_enc = *data;
+        enc.encrypt(data_enc.as_mut_slice().into());
+
+        // Without shanda the payload is just AES encrypted
+        let mut data_aes = *data;
+        ShroomAESCipher::default().crypt(key, data_aes.as_mut_slice().into());
+        assert_eq!(data_enc, data_aes);
+
+        dec.decrypt(data_enc.as_mut_slice().into());
+        assert_eq!(*data, data_enc);
+        assert_eq!(enc.round_key, dec.round_key);
+    }
 }
@@ -0,0 +1,64 @@
+use cipher::inout::InOutBuf;
+
+use crate::{NetError, NetResult};
+
+use super::{ExtendedLenHeader, PacketCipher, PacketHeader};
+
+/// Cipher which only frames the packets and keeps the payload in plaintext
+/// The header is the length as little endian `u32`
+/// Should only be used for internal links or debugging, the client doesn't support It
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullCipher;
+
+impl PacketCipher for NullCipher {
+    fn encode_header(&self, length: u16) -> PacketHeader {
+        (length as u32).to_le_bytes()
+    }
+
+    fn decode_header(&self, hdr: PacketHeader) -> NetResult<u16> {
+        let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+        let high = u16::from_le_bytes([hdr[2], hdr[3]]);
+        if high != 0 {
+            return Err(NetError::InvalidHeader {
+                len,
+                key: high,
+                expected_key: 0,
+            });
+        }
+
+        Ok(len)
+    }
+
+    fn encode_extended_len(&self, length: u32) -> ExtendedLenHeader {
+        length.to_le_bytes()
+    }
+
+    fn decode_extended_len(&self, hdr: ExtendedLenHeader) -> u32 {
+        u32::from_le_bytes(hdr)
+    }
+
+    fn encrypt(&mut self, _data: InOutBuf<u8>) {}
+
+    fn decrypt(&mut self, _data: InOutBuf<u8>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::PacketCipher;
+
+    use super::NullCipher;
+
+    #[test]
+    fn header() {
+        let mut cipher = NullCipher;
+        for ln in [0, 1, 1024, u16::MAX] {
+            let hdr = cipher.encode_header(ln);
+            assert_eq!(cipher.decode_header(hdr).unwrap(), ln);
+        }
+        assert!(cipher.decode_header([1, 0, 1, 0]).is_err());
+
+        let mut data = *b"abcdef";
+        cipher.encrypt(data.as_mut_slice().into());
+        assert_eq!(&data, b"abcdef");
+    }
+}
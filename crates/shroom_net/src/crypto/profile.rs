@@ -0,0 +1,114 @@
+use crate::net::codec::handshake::LocaleCode;
+
+use super::ShroomVersion;
+
+/// Global clients dropped the shanda cipher with this version,
+/// the other official locales are assumed to follow the global client
+pub const SHANDA_REMOVED_VERSION: u16 = 118;
+
+/// Variant of the AES layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AesVariant {
+    /// AES-OFB, the IV is the expanded round key and It's re-used for every 1460 byte chunk
+    Ofb,
+    /// Payloads are not AES encrypted
+    Disabled,
+}
+
+/// Scheme of the packet header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderScheme {
+    /// The length is xored with the round key and the version
+    Versioned,
+    /// Versioned header, lengths from the `EXTENDED_LEN_MARKER` on
+    /// are sent as marker followed by the extended length.
+    /// Used by the custom clients of `LocaleCode::Other`
+    Extended,
+}
+
+/// Describes which layers the crypto applies for a client version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptoProfile {
+    /// Whether the shanda cipher is applied before the AES layer
+    pub shanda: bool,
+    /// AES layer
+    pub aes: AesVariant,
+    /// Header scheme
+    pub header: HeaderScheme,
+}
+
+impl Default for CryptoProfile {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
+impl CryptoProfile {
+    /// Profile with Shanda and AES-OFB
+    pub const LEGACY: Self = Self {
+        shanda: true,
+        aes: AesVariant::Ofb,
+        header: HeaderScheme::Versioned,
+    };
+
+    /// Profile without Shanda for newer clients
+    pub const AES_ONLY: Self = Self {
+        shanda: false,
+        aes: AesVariant::Ofb,
+        header: HeaderScheme::Versioned,
+    };
+
+    /// Profile without Shanda and with the extended header for custom clients
+    pub const EXTENDED: Self = Self {
+        shanda: false,
+        aes: AesVariant::Ofb,
+        header: HeaderScheme::Extended,
+    };
+
+    /// Picks the profile for the given major version and locale
+    pub fn from_version(version: ShroomVersion, locale: LocaleCode) -> Self {
+        match locale {
+            // Custom clients don't have to stay compatible with the official builds
+            LocaleCode::Other(_) => Self::EXTENDED,
+            _ if version.0 >= SHANDA_REMOVED_VERSION => Self::AES_ONLY,
+            _ => Self::LEGACY,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::ShroomVersion, net::codec::handshake::LocaleCode};
+
+    use super::{CryptoProfile, HeaderScheme, SHANDA_REMOVED_VERSION};
+
+    #[test]
+    fn from_version() {
+        for locale in [LocaleCode::Global, LocaleCode::Europe, LocaleCode::Korea] {
+            assert_eq!(
+                CryptoProfile::from_version(ShroomVersion(83), locale),
+                CryptoProfile::LEGACY
+            );
+            assert_eq!(
+                CryptoProfile::from_version(ShroomVersion(SHANDA_REMOVED_VERSION - 1), locale),
+                CryptoProfile::LEGACY
+            );
+            assert_eq!(
+                CryptoProfile::from_version(ShroomVersion(SHANDA_REMOVED_VERSION), locale),
+                CryptoProfile::AES_ONLY
+            );
+            assert_eq!(
+                CryptoProfile::from_version(ShroomVersion(176), locale),
+                CryptoProfile::AES_ONLY
+            );
+        }
+
+        // Custom clients use the extended header regardless of the version
+        for version in [83, 176] {
+            let profile =
+                CryptoProfile::from_version(ShroomVersion(version), LocaleCode::Other(0x42));
+            assert_eq!(profile, CryptoProfile::EXTENDED);
+            assert_eq!(profile.header, HeaderScheme::Extended);
+        }
+    }
+}
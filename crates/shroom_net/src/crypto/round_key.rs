@@ -3,8 +3,6 @@ use rand::{CryptoRng, Rng, RngCore};
 
 use super::{ig_cipher::IgContext, ROUND_KEY_LEN};
 
-
-
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq)]
 pub struct RoundKey(pub [u8; ROUND_KEY_LEN]);
 
@@ -108,7 +108,6 @@ impl ShandaCipher {
 mod tests {
     use crate::crypto::shanda_cipher::ShandaCipher;
 
-
     #[test]
     fn en_dec_shanda() {
         let data = b"abcdef";
@@ -3,7 +3,10 @@ use std::{fmt::Display, io, str::Utf8Error};
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 use thiserror::Error;
 
-use crate::packet::packet_data_context::PacketDataContext;
+use crate::{
+    opcode::SessionPhase,
+    packet::{packet_data_context::PacketDataContext, StringCodec},
+};
 
 #[derive(Debug)]
 pub struct EOFErrorData {
@@ -27,6 +30,12 @@ pub enum NetError {
     EOF(Box<EOFErrorData>),
     #[error("String limit {0} exceeed")]
     StringLimit(usize),
+    #[error("Unable to en/decode string with codec {0:?}")]
+    StringCodec(StringCodec),
+    #[error("String codec {0:?} requires the `encoding` feature")]
+    UnsupportedStringCodec(StringCodec),
+    #[error("String with codec {0:?} can not be borrowed")]
+    StringBorrow(StringCodec),
     #[error("Invalid header with key: {key:X}, expected: {expected_key:X}, len: {len}")]
     InvalidHeader {
         len: u16,
@@ -39,10 +48,16 @@ pub enum NetError {
     InvalidEnumPrimitive(u32),
     #[error("Frame of length {0} is too large.")]
     FrameSize(usize),
+    #[error("Frame of length {len} exceeds the max frame length {max}")]
+    MaxFrameLen { len: usize, max: usize },
+    #[error("Frame of length {0} requires the extended length header")]
+    HeaderLenLimit(usize),
     #[error("Handshake of length {0} is too large.")]
     HandshakeSize(usize),
     #[error("Unable to read handshake")]
     InvalidHandshake,
+    #[error("Handshake mismatch, expected: {expected}, got: {got}")]
+    HandshakeMismatch { expected: String, got: String },
     #[error("Invalid AES key")]
     InvalidAESKey,
     #[error("Invalid timestamp: {0}")]
@@ -55,6 +70,28 @@ pub enum NetError {
     OutOfCapacity,
     #[error("Ping timeout")]
     PingTimeout,
+    #[error("Read idle timeout")]
+    ReadIdleTimeout,
+    #[error("Write stall timeout")]
+    WriteStallTimeout,
+    #[error("Rate limit exceeded by opcode: {0:?}")]
+    RateLimited(Option<u16>),
+    #[error("Invalid rate limit: {0}")]
+    InvalidRateLimit(&'static str),
+    #[error("Request timeout, while waiting for opcode: {0:X}")]
+    RequestTimeout(u16),
+    #[error("Request was cancelled, while waiting for opcode: {0:X}")]
+    RequestCancelled(u16),
+    #[error("Too many packets were read, while waiting for opcode: {0:X}")]
+    RequestBacklog(u16),
+    #[error("Unexpected opcode {opcode:X} in phase {phase:?}")]
+    UnexpectedOpcode { phase: SessionPhase, opcode: u16 },
+    #[error("Packet with opcode {0:X} was rejected")]
+    Rejected(u16),
+    #[error("Handler panicked: {0}")]
+    HandlerPanic(String),
+    #[error("Session pipe missed frames")]
+    MissedFrame,
 }
 
 impl NetError {
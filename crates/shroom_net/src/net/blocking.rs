@@ -0,0 +1,296 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+};
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use crate::{
+    crypto::SharedCryptoContext, packet::StringCodec, EncodePacket, HasOpcode, NetOpcode,
+    NetResult, PacketBuffer, ShroomPacket,
+};
+
+use super::{
+    codec::{
+        handshake::{Handshake, HandshakePolicy},
+        packet_codec::PacketCodec,
+    },
+    session::encode_packet_with_opcode,
+};
+
+const READ_CHUNK_LEN: usize = 4096;
+
+/// Blocking version of `ShroomSession` for any `Read + Write` transport,
+/// which doesn't require a runtime
+pub struct BlockingShroomSession<T> {
+    io: T,
+    codec: PacketCodec,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    encode_buffer: BytesMut,
+}
+
+impl<T> BlockingShroomSession<T>
+where
+    T: Read + Write,
+{
+    /// Create a new session from the `io` and the codec
+    pub fn new(io: T, codec: PacketCodec) -> Self {
+        Self {
+            io,
+            codec,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            encode_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Initialize a server session, by sending out the given handshake
+    pub fn initialize_server_session(
+        mut io: T,
+        ctx: SharedCryptoContext,
+        handshake: Handshake,
+    ) -> NetResult<Self> {
+        handshake.write_handshake(&mut io)?;
+        Ok(Self::from_server_handshake(io, ctx, handshake))
+    }
+
+    /// Initialize a client session, by reading a handshake first
+    pub fn initialize_client_session(
+        io: T,
+        ctx: SharedCryptoContext,
+    ) -> NetResult<(Self, Handshake)> {
+        Self::initialize_client_session_with_policy(io, ctx, &HandshakePolicy::default())
+    }
+
+    /// Initialize a client session, by reading a handshake first
+    /// which has to pass the policy
+    pub fn initialize_client_session_with_policy(
+        mut io: T,
+        ctx: SharedCryptoContext,
+        policy: &HandshakePolicy,
+    ) -> NetResult<(Self, Handshake)> {
+        let handshake = Handshake::read_handshake(&mut io)?;
+        policy.check(&handshake)?;
+        let sess = Self::from_client_handshake(io, ctx, handshake.clone());
+
+        Ok((sess, handshake))
+    }
+
+    /// Create a server session from a handshake
+    pub fn from_server_handshake(io: T, ctx: SharedCryptoContext, handshake: Handshake) -> Self {
+        Self::new(io, PacketCodec::from_server_handshake(ctx, handshake))
+    }
+
+    /// Create a client session from a handshake
+    pub fn from_client_handshake(io: T, ctx: SharedCryptoContext, handshake: Handshake) -> Self {
+        Self::new(io, PacketCodec::from_client_handshake(ctx, handshake))
+    }
+
+    /// Codec which is used for the strings of this session
+    pub fn str_codec(&self) -> StringCodec {
+        self.codec.str_codec()
+    }
+
+    /// Reference to the underlying transport
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Mutable reference to the underlying transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Returns the underlying transport, buffered data is lost
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    pub fn read_packet(&mut self) -> NetResult<ShroomPacket> {
+        loop {
+            if let Some(pkt) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(pkt);
+            }
+
+            let mut chunk = [0; READ_CHUNK_LEN];
+            let n = match self.io.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => n,
+                // Reads which were interrupted by a signal are retried like `read_exact` does
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub fn send_packet_buffer(&mut self, buf: &PacketBuffer) -> NetResult<()> {
+        self.send_packets(buf.packets())
+    }
+
+    /// Sends all packets with a single write, see `ShroomSession::send_packets`
+    pub fn send_packets<B: AsRef<[u8]>>(
+        &mut self,
+        pkts: impl IntoIterator<Item = B>,
+    ) -> NetResult<()> {
+        self.write_buf.clear();
+        let res = self.codec.encode_all(pkts, &mut self.write_buf);
+        // Frames which were encoded before an error have to be sent regardless
+        self.io.write_all(&self.write_buf)?;
+        self.io.flush()?;
+        res
+    }
+
+    pub fn send_packet(&mut self, data: &[u8]) -> NetResult<()> {
+        self.send_packets([data])
+    }
+
+    pub fn send_encode_packet<P: EncodePacket + HasOpcode>(&mut self, data: P) -> NetResult<()> {
+        self.send_encode_packet_with_opcode(P::OPCODE, data)
+    }
+
+    pub fn send_encode_packet_with_opcode(
+        &mut self,
+        op: impl NetOpcode,
+        data: impl EncodePacket,
+    ) -> NetResult<()> {
+        let str_codec = self.str_codec();
+        let mut encode_buffer = std::mem::take(&mut self.encode_buffer);
+        let res = encode_packet_with_opcode(&mut encode_buffer, str_codec, op, data)
+            .and_then(|_| self.send_packet(&encode_buffer));
+        self.encode_buffer = encode_buffer;
+        res
+    }
+}
+
+impl BlockingShroomSession<TcpStream> {
+    /// Get the peer address of the socket
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.io.peer_addr()
+    }
+
+    /// Get the local address of the socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Connect to the given addr with the crypto context
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        ctx: SharedCryptoContext,
+    ) -> NetResult<(Self, Handshake)> {
+        Self::connect_with_policy(addr, ctx, &HandshakePolicy::default())
+    }
+
+    /// Connect to the given addr with the crypto context,
+    /// the handshake has to pass the policy
+    pub fn connect_with_policy(
+        addr: impl ToSocketAddrs,
+        ctx: SharedCryptoContext,
+        policy: &HandshakePolicy,
+    ) -> NetResult<(Self, Handshake)> {
+        let socket = TcpStream::connect(addr)?;
+        Self::initialize_client_session_with_policy(socket, ctx, policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read, Write},
+        net::TcpListener,
+    };
+
+    use crate::{
+        crypto::SharedCryptoContext,
+        net::{
+            codec::packet_codec::PacketCodec,
+            service::{BasicHandshakeGenerator, HandshakeGenerator},
+        },
+        packet::StringCodec,
+    };
+
+    use super::BlockingShroomSession;
+
+    /// Transport which reads a single byte at a time and is interrupted before every read
+    struct InterruptedIo {
+        data: Cursor<Vec<u8>>,
+        interrupt: bool,
+    }
+
+    impl Read for InterruptedIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(1);
+            self.data.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for InterruptedIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_interrupted() -> anyhow::Result<()> {
+        let mut server = BlockingShroomSession::new(
+            Cursor::new(Vec::new()),
+            PacketCodec::plaintext(StringCodec::Utf8),
+        );
+        server.send_packet(&[1, 2, 3])?;
+
+        let io = InterruptedIo {
+            data: Cursor::new(server.into_inner().into_inner()),
+            interrupt: false,
+        };
+        let mut client = BlockingShroomSession::new(io, PacketCodec::plaintext(StringCodec::Utf8));
+        assert_eq!(client.read_packet()?.as_ref(), &[1, 2, 3][..]);
+        // The end of the data is still an error
+        assert!(client.read_packet().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn echo() -> anyhow::Result<()> {
+        const ECHO_DATA: [&[u8]; 4] = [&[0xFF; 8192], &[1, 2], &[], &[0x0; 1024]];
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let server = std::thread::spawn(move || -> anyhow::Result<()> {
+            let socket = listener.accept()?.0;
+            let handshake = BasicHandshakeGenerator::v83().generate_handshake();
+            let mut sess = BlockingShroomSession::initialize_server_session(
+                socket,
+                SharedCryptoContext::default(),
+                handshake,
+            )?;
+
+            for _ in ECHO_DATA.iter() {
+                let pkt = sess.read_packet()?;
+                sess.send_packet(pkt.as_ref())?;
+            }
+            Ok(())
+        });
+
+        let (mut sess, _) = BlockingShroomSession::connect(addr, SharedCryptoContext::default())?;
+        for data in ECHO_DATA.iter() {
+            sess.send_packet(data)?;
+            let pkt = sess.read_packet()?;
+            assert_eq!(pkt.as_ref(), *data);
+        }
+
+        server.join().unwrap()?;
+        Ok(())
+    }
+}
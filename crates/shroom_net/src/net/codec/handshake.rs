@@ -1,12 +1,19 @@
-use std::{io::{Read, Write}, str::FromStr};
+use std::{
+    cmp::Ordering,
+    fmt,
+    io::{Read, Write},
+    ops::RangeInclusive,
+    str::FromStr,
+};
 
 use anyhow::anyhow;
+use bytes::BufMut;
 use rand::{RngCore, CryptoRng};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     packet::PacketWrapped,
-    DecodePacket, EncodePacket, NetError, NetResult, PacketWriter, crypto::{RoundKey, ROUND_KEY_LEN}, shroom_enum_code, util::must_init_array_str,
+    DecodePacket, EncodePacket, NetError, NetResult, PacketReader, PacketWriter, SizeHint, crypto::{RoundKey, ROUND_KEY_LEN}, util::must_init_array_str,
 };
 
 use super::MAX_HANDSHAKE_LEN;
@@ -14,37 +21,169 @@ use super::MAX_HANDSHAKE_LEN;
 /// Handshake buffer
 pub type HandshakeBuf = [u8; MAX_HANDSHAKE_LEN + 2];
 
-//Locale code for handshake, T means test server
-shroom_enum_code!(
-    LocaleCode,
-    u8,
+/// Locale code for handshake, T means test server
+/// Unknown codes of custom clients are kept as `Other`,
+/// known codes are always converted to their named variant
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    num_enum::FromPrimitive,
+    num_enum::IntoPrimitive,
+)]
+#[repr(u8)]
+pub enum LocaleCode {
     Korea = 1,
     KoreaT = 2,
     Japan = 3,
     China = 4,
-    ChinaT = 5, 
+    ChinaT = 5,
     Taiwan = 6,
     TaiwanT = 7,
     Global = 8,
     Europe = 9,
-    RlsPe = 10
-);
+    RlsPe = 10,
+    #[num_enum(catch_all)]
+    Other(u8),
+}
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
+impl PacketWrapped for LocaleCode {
+    type Inner = u8;
+
+    fn packet_into_inner(&self) -> Self::Inner {
+        u8::from(*self)
+    }
+
+    fn packet_from(v: Self::Inner) -> Self {
+        Self::from(v)
+    }
+}
+
+/// Max length of the sub version, so the handshake fits within `MAX_HANDSHAKE_LEN`
+/// The remaining fields are the version, the string length, both IVs and the locale
+pub const MAX_SUB_VERSION_LEN: usize = MAX_HANDSHAKE_LEN - (2 + 2 + ROUND_KEY_LEN * 2 + 1);
+
+/// Sub version string of the handshake like "1" or "1:0"
+/// Ordering compares the components separated by ':' or '.' numerically If possible
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubVersion {
+    len: u8,
+    data: [u8; MAX_SUB_VERSION_LEN],
+}
+
+impl SubVersion {
+    /// Creates the sub version, panics If the string exceeds `MAX_SUB_VERSION_LEN`
+    pub const fn must_parse(s: &str) -> Self {
+        Self {
+            len: s.len() as u8,
+            data: must_init_array_str(s),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.data[..self.len as usize]).expect("Sub version must be valid")
+    }
+
+    fn components(&self) -> impl Iterator<Item = &str> {
+        self.as_str().split([':', '.'])
+    }
+}
+
+impl FromStr for SubVersion {
+    type Err = NetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_SUB_VERSION_LEN {
+            return Err(NetError::StringLimit(MAX_SUB_VERSION_LEN));
+        }
+
+        Ok(Self::must_parse(s))
+    }
+}
+
+impl fmt::Display for SubVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for SubVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SubVersion").field(&self.as_str()).finish()
+    }
+}
+
+impl Ord for SubVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let cmp_component = |(a, b): (&str, &str)| match (a.parse::<u32>(), b.parse::<u32>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+
+        let mut lhs = self.components();
+        let mut rhs = other.components();
+        loop {
+            match (lhs.next(), rhs.next()) {
+                (Some(a), Some(b)) => match cmp_component((a, b)) {
+                    Ordering::Equal => continue,
+                    ord => return ord,
+                },
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                // Keep the ordering consistent with Eq for "1" and "01"
+                (None, None) => return self.as_str().cmp(other.as_str()),
+            }
+        }
+    }
+}
+
+impl PartialOrd for SubVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Encoded like a string, but It's always plain bytes because It's not bound to a session
+impl EncodePacket for SubVersion {
+    fn encode_packet<B: BufMut>(&self, pw: &mut PacketWriter<B>) -> NetResult<()> {
+        pw.write_u16(self.len as u16)?;
+        pw.write_bytes(&self.data[..self.len as usize])
+    }
+
+    const SIZE_HINT: SizeHint = SizeHint::NONE;
+
+    fn packet_len(&self) -> usize {
+        2 + self.len as usize
+    }
+}
+
+impl<'de> DecodePacket<'de> for SubVersion {
+    fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
+        let n = pr.read_u16()? as usize;
+        if n > MAX_SUB_VERSION_LEN {
+            return Err(NetError::StringLimit(MAX_SUB_VERSION_LEN));
+        }
+
+        let s = std::str::from_utf8(pr.read_bytes(n)?)?;
+        Ok(Self::must_parse(s))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct HandshakeVersion {
     pub version: u16,
-    pub sub_version_len: u16,
     pub sub_version: SubVersion,
 }
 
-pub type SubVersion = [u8; 1];
-
-
 impl HandshakeVersion {
     pub const fn new(version: u16, subversion: SubVersion) -> Self {
         Self {
             version,
-            sub_version_len: 1,
             sub_version: subversion
         }
     }
@@ -54,11 +193,7 @@ impl HandshakeVersion {
     }
 
     pub const fn must_parse(version: u16, subversion: &str) -> Self {
-        Self {
-            version,
-            sub_version_len: 1,
-            sub_version: must_init_array_str(subversion)
-        }
+        Self::new(version, SubVersion::must_parse(subversion))
     }
 
     pub const fn v83() -> Self {
@@ -78,11 +213,17 @@ impl FromStr for HandshakeVersion {
         
         Ok(Self::new(
             major.parse()?,
-            minor.as_bytes().try_into()?
+            minor.parse()?
         ))
     }
 }
 
+impl fmt::Display for HandshakeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.version, self.sub_version)
+    }
+}
+
 /// Codec Handshake
 #[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
 pub struct Handshake {
@@ -170,10 +311,76 @@ impl Handshake {
     }
 }
 
+/// Policy which is used by clients to validate the handshake of the server
+/// The default policy accepts every handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakePolicy {
+    /// Accepted versions, If set
+    pub versions: Option<RangeInclusive<HandshakeVersion>>,
+    /// Accepted locales, an empty list accepts every locale
+    pub locales: Vec<LocaleCode>,
+    /// Pinned (`iv_enc`, `iv_dec`), mostly useful for tests
+    pub ivs: Option<(RoundKey, RoundKey)>,
+}
+
+impl HandshakePolicy {
+    /// Only accepts the exact version
+    pub fn with_version(self, version: HandshakeVersion) -> Self {
+        self.with_version_range(version..=version)
+    }
+
+    /// Only accepts versions within the range
+    pub fn with_version_range(mut self, versions: RangeInclusive<HandshakeVersion>) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    /// Only accepts the given locales
+    pub fn with_locales(mut self, locales: impl IntoIterator<Item = LocaleCode>) -> Self {
+        self.locales = locales.into_iter().collect();
+        self
+    }
+
+    /// Only accepts the given IVs
+    pub fn with_pinned_ivs(mut self, iv_enc: RoundKey, iv_dec: RoundKey) -> Self {
+        self.ivs = Some((iv_enc, iv_dec));
+        self
+    }
+
+    /// Checks the handshake against the policy
+    pub fn check(&self, handshake: &Handshake) -> NetResult<()> {
+        let mismatch = |expected: String, got: String| NetError::HandshakeMismatch { expected, got };
+
+        if let Some(versions) = &self.versions {
+            if !versions.contains(&handshake.version) {
+                return Err(mismatch(
+                    format!("version {}..={}", versions.start(), versions.end()),
+                    format!("version {}", handshake.version),
+                ));
+            }
+        }
+
+        if !self.locales.is_empty() && !self.locales.contains(&handshake.locale) {
+            return Err(mismatch(
+                format!("locale {:?}", self.locales),
+                format!("locale {:?}", handshake.locale),
+            ));
+        }
+
+        if let Some(ivs) = self.ivs {
+            let got = (handshake.iv_enc, handshake.iv_dec);
+            if ivs != got {
+                return Err(mismatch(format!("ivs {ivs:?}"), format!("ivs {got:?}")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 // Wrapper to implement encode/decode
 impl PacketWrapped for Handshake {
     type Inner = (
-        u16,
         u16,
         SubVersion,
         [u8; ROUND_KEY_LEN],
@@ -184,7 +391,6 @@ impl PacketWrapped for Handshake {
     fn packet_into_inner(&self) -> Self::Inner {
         (
             self.version.version,
-            self.version.sub_version_len,
             self.version.sub_version,
             self.iv_enc.0,
             self.iv_dec.0,
@@ -194,19 +400,19 @@ impl PacketWrapped for Handshake {
 
     fn packet_from(v: Self::Inner) -> Self {
         Self {
-            version: HandshakeVersion::new(v.0, v.2),
-            iv_enc: RoundKey(v.3),
-            iv_dec: RoundKey(v.4),
-            locale: v.5,
+            version: HandshakeVersion::new(v.0, v.1),
+            iv_enc: RoundKey(v.2),
+            iv_dec: RoundKey(v.3),
+            locale: v.4,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{DecodePacket, EncodePacket, PacketWriter, ShroomPacket, crypto::RoundKey, net::codec::handshake::{LocaleCode, HandshakeVersion}};
+    use crate::{DecodePacket, EncodePacket, PacketWriter, ShroomPacket, crypto::RoundKey, net::codec::{handshake::{LocaleCode, HandshakeVersion}, MAX_HANDSHAKE_LEN}};
 
-    use super::Handshake;
+    use super::{Handshake, HandshakePolicy, SubVersion, MAX_SUB_VERSION_LEN};
 
     #[test]
     fn test_handshake_encode_decode() {
@@ -225,4 +431,87 @@ mod tests {
 
         assert_eq!(handshake, dec);
     }
+
+    #[test]
+    fn sub_version_handshake() {
+        let handshake = Handshake {
+            version: HandshakeVersion::must_parse(95, "1:0"),
+            iv_enc: RoundKey([1u8; 4]),
+            iv_dec: RoundKey([2u8; 4]),
+            locale: LocaleCode::Global,
+        };
+
+        let mut buf = Vec::new();
+        handshake.write_handshake(&mut buf).unwrap();
+        let dec = Handshake::read_handshake(buf.as_slice()).unwrap();
+        assert_eq!(handshake, dec);
+
+        let max: SubVersion = "1".repeat(MAX_SUB_VERSION_LEN).parse().unwrap();
+        let handshake = Handshake {
+            version: HandshakeVersion::new(95, max),
+            ..handshake
+        };
+        let mut buf = Vec::new();
+        handshake.write_handshake(&mut buf).unwrap();
+        assert_eq!(buf.len(), MAX_HANDSHAKE_LEN + 2);
+        assert_eq!(Handshake::read_handshake(buf.as_slice()).unwrap(), handshake);
+    }
+
+    #[test]
+    fn sub_version_parse_ord() {
+        assert!("1".repeat(MAX_SUB_VERSION_LEN + 1).parse::<SubVersion>().is_err());
+        let v: HandshakeVersion = "95.1:0".parse().unwrap();
+        assert_eq!(v, HandshakeVersion::must_parse(95, "1:0"));
+        assert_eq!(v.to_string(), "95.1:0");
+
+        let sub = |s: &str| s.parse::<SubVersion>().unwrap();
+        assert!(sub("1") < sub("1:0"));
+        assert!(sub("1:2") < sub("1:10"));
+        assert!(sub("2") > sub("1:9"));
+        assert!(HandshakeVersion::must_parse(83, "9") < HandshakeVersion::must_parse(95, "1"));
+        assert!(HandshakeVersion::v95() < HandshakeVersion::must_parse(95, "1:1"));
+    }
+
+    #[test]
+    fn locale_other() {
+        assert_eq!(LocaleCode::from(8), LocaleCode::Global);
+        assert_eq!(LocaleCode::from(0x42), LocaleCode::Other(0x42));
+        assert_eq!(u8::from(LocaleCode::Other(0x42)), 0x42);
+
+        for locale in [LocaleCode::Other(0), LocaleCode::Other(0xFF), LocaleCode::Europe] {
+            let handshake = Handshake::new_random(HandshakeVersion::v83(), locale, rand::thread_rng());
+            let mut buf = Vec::new();
+            handshake.write_handshake(&mut buf).unwrap();
+            assert_eq!(Handshake::read_handshake(buf.as_slice()).unwrap(), handshake);
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let handshake = Handshake {
+            version: HandshakeVersion::must_parse(95, "1:0"),
+            iv_enc: RoundKey([1u8; 4]),
+            iv_dec: RoundKey([2u8; 4]),
+            locale: LocaleCode::Global,
+        };
+
+        assert!(HandshakePolicy::default().check(&handshake).is_ok());
+        assert!(HandshakePolicy::default()
+            .with_version_range(HandshakeVersion::v95()..=HandshakeVersion::must_parse(95, "2"))
+            .with_locales([LocaleCode::Global, LocaleCode::Europe])
+            .with_pinned_ivs(RoundKey([1u8; 4]), RoundKey([2u8; 4]))
+            .check(&handshake)
+            .is_ok());
+
+        for policy in [
+            HandshakePolicy::default().with_version(HandshakeVersion::v95()),
+            HandshakePolicy::default().with_locales([LocaleCode::Korea]),
+            HandshakePolicy::default().with_pinned_ivs(RoundKey([2u8; 4]), RoundKey([1u8; 4])),
+        ] {
+            assert!(matches!(
+                policy.check(&handshake),
+                Err(crate::NetError::HandshakeMismatch { .. })
+            ));
+        }
+    }
 }
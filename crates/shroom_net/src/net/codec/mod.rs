@@ -2,7 +2,7 @@ pub mod handshake;
 pub mod packet_codec;
 
 pub use handshake::Handshake;
-pub use packet_codec::PacketCodec;
+pub use packet_codec::{FrameConfig, PacketCodec};
 
 use crate::{NetError, ShroomPacket};
 
@@ -17,3 +17,5 @@ pub trait ShroomCodec {
 
 pub const MAX_HANDSHAKE_LEN: usize = 24;
 pub const MAX_PACKET_LEN: usize = i16::MAX as usize;
+/// Default max length for frames with the extended length header
+pub const MAX_EXTENDED_PACKET_LEN: usize = 1024 * 1024;
@@ -1,20 +1,85 @@
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
-    crypto::{PacketHeader, SharedCryptoContext, ShroomCrypto, ShroomVersion, PACKET_HEADER_LEN},
+    crypto::{
+        BoxedPacketCipher, ExtendedLenHeader, HeaderScheme, NullCipher, PacketCipher, PacketHeader,
+        RoundKey, SharedCryptoContext, ShroomCrypto, ShroomVersion, EXTENDED_LEN_HEADER_LEN,
+        EXTENDED_LEN_MARKER, PACKET_HEADER_LEN,
+    },
+    packet::StringCodec,
     NetError, NetResult, ShroomPacket,
 };
 
-use super::{handshake::Handshake, ShroomCodec, MAX_PACKET_LEN};
+use super::{handshake::Handshake, ShroomCodec, MAX_EXTENDED_PACKET_LEN, MAX_PACKET_LEN};
 
-/// Check the packet length
-fn check_packet_len(len: usize) -> NetResult<()> {
-    if len > MAX_PACKET_LEN {
-        return Err(NetError::FrameSize(len));
+/// Limits and header options for the frames of a codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameConfig {
+    /// Max length of the payload of a frame
+    pub max_frame_len: usize,
+    /// Whether lengths from the `EXTENDED_LEN_MARKER` on are sent with the extended length header
+    pub extended_len: bool,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_len: MAX_PACKET_LEN,
+            extended_len: false,
+        }
+    }
+}
+
+impl FrameConfig {
+    /// Config with the extended length header
+    pub const fn extended(max_frame_len: usize) -> Self {
+        Self {
+            max_frame_len,
+            extended_len: true,
+        }
+    }
+
+    /// Default config for the header scheme
+    pub fn from_header_scheme(scheme: HeaderScheme) -> Self {
+        match scheme {
+            HeaderScheme::Versioned => Self::default(),
+            HeaderScheme::Extended => Self::extended(MAX_EXTENDED_PACKET_LEN),
+        }
+    }
+
+    /// Sets the max frame length
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
     }
 
-    Ok(())
+    /// Check the packet length against the max frame length
+    /// and whether the header is able to represent the length
+    fn check_len(&self, len: usize) -> NetResult<()> {
+        if len > self.max_frame_len {
+            return Err(NetError::MaxFrameLen {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+
+        let header_limit = if self.extended_len {
+            u32::MAX as usize
+        } else {
+            u16::MAX as usize
+        };
+        if len > header_limit {
+            return Err(NetError::HeaderLenLimit(len));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the length requires the extended length header
+    fn is_extended(&self, len: usize) -> bool {
+        self.extended_len && len >= EXTENDED_LEN_MARKER as usize
+    }
 }
 
 pub struct PacketCodec {
@@ -23,20 +88,103 @@ pub struct PacketCodec {
 }
 
 impl PacketCodec {
-    pub fn from_client_handshake(ctx: SharedCryptoContext, handshake: Handshake) -> Self {
-        let v = ShroomVersion(handshake.version.major());
+    /// Creates a codec with a cipher for each direction
+    pub fn new(
+        decode: impl PacketCipher + Send + 'static,
+        encode: impl PacketCipher + Send + 'static,
+        str_codec: StringCodec,
+    ) -> Self {
         Self {
-            decode: PacketDecodeCodec(ShroomCrypto::new(ctx.clone(), handshake.iv_dec, v.invert())),
-            encode: PacketEncodeCodec(ShroomCrypto::new(ctx, handshake.iv_enc, v)),
+            decode: PacketDecodeCodec {
+                cipher: Box::new(decode),
+                str_codec,
+                frame: FrameConfig::default(),
+            },
+            encode: PacketEncodeCodec {
+                cipher: Box::new(encode),
+                frame: FrameConfig::default(),
+            },
         }
     }
 
+    /// Sets the frame config for both directions
+    pub fn with_frame_config(mut self, frame: FrameConfig) -> Self {
+        self.decode.frame = frame;
+        self.encode.frame = frame;
+        self
+    }
+
+    /// Frame config of the codec
+    pub fn frame_config(&self) -> FrameConfig {
+        self.decode.frame
+    }
+
+    /// Creates a codec which frames the packets but keeps the payloads in plaintext
+    pub fn plaintext(str_codec: StringCodec) -> Self {
+        Self::new(NullCipher, NullCipher, str_codec)
+    }
+
+    /// Creates the codec from the handshake, the crypto profile is picked based on the version
+    /// and the locale. If the context is set to plaintext the `NullCipher` is used
+    fn from_handshake(
+        ctx: SharedCryptoContext,
+        handshake: &Handshake,
+        (dec_key, dec_version): (RoundKey, ShroomVersion),
+        (enc_key, enc_version): (RoundKey, ShroomVersion),
+    ) -> Self {
+        let str_codec = handshake.locale.into();
+        let profile = ctx.profile_for(ShroomVersion(handshake.version.major()), handshake.locale);
+        let mut frame = FrameConfig::from_header_scheme(profile.header);
+        if let Some(max_frame_len) = ctx.max_frame_len {
+            frame = frame.with_max_frame_len(max_frame_len);
+        }
+
+        let codec = if ctx.plaintext {
+            Self::plaintext(str_codec)
+        } else {
+            Self::new(
+                ShroomCrypto::with_profile(ctx.clone(), dec_key, dec_version, profile),
+                ShroomCrypto::with_profile(ctx, enc_key, enc_version, profile),
+                str_codec,
+            )
+        };
+        codec.with_frame_config(frame)
+    }
+
+    /// Creates the codec for a client
+    pub fn from_client_handshake(ctx: SharedCryptoContext, handshake: Handshake) -> Self {
+        let v = ShroomVersion(handshake.version.major());
+        Self::from_handshake(
+            ctx,
+            &handshake,
+            (handshake.iv_dec, v.invert()),
+            (handshake.iv_enc, v),
+        )
+    }
+
+    /// Creates the codec for a server
     pub fn from_server_handshake(ctx: SharedCryptoContext, handshake: Handshake) -> Self {
         let v = ShroomVersion(handshake.version.major());
-        Self {
-            decode: PacketDecodeCodec(ShroomCrypto::new(ctx.clone(), handshake.iv_enc, v)),
-            encode: PacketEncodeCodec(ShroomCrypto::new(ctx, handshake.iv_dec, v.invert())),
-        }
+        Self::from_handshake(
+            ctx,
+            &handshake,
+            (handshake.iv_enc, v),
+            (handshake.iv_dec, v.invert()),
+        )
+    }
+
+    /// Codec for the strings, which is based on the locale of the handshake
+    pub fn str_codec(&self) -> StringCodec {
+        self.decode.str_codec
+    }
+
+    /// Encodes all frames onto the buffer
+    pub fn encode_all<B: AsRef<[u8]>>(
+        &mut self,
+        items: impl IntoIterator<Item = B>,
+        dst: &mut BytesMut,
+    ) -> NetResult<()> {
+        self.encode.encode_all(items, dst)
     }
 }
 
@@ -45,7 +193,12 @@ impl ShroomCodec for PacketCodec {
     type Decoder = PacketDecodeCodec;
 }
 
-pub struct PacketDecodeCodec(pub ShroomCrypto);
+pub struct PacketDecodeCodec {
+    pub cipher: BoxedPacketCipher,
+    /// Codec which is attached to the decoded packets
+    pub str_codec: StringCodec,
+    pub frame: FrameConfig,
+}
 
 impl Decoder for PacketCodec {
     type Item = ShroomPacket;
@@ -65,13 +218,26 @@ impl Decoder for PacketDecodeCodec {
             return Ok(None);
         }
         let hdr: PacketHeader = src[..PACKET_HEADER_LEN].try_into().expect("Packet header");
-        let length = self.0.decode_header(hdr)? as usize;
+        let mut length = self.cipher.decode_header(hdr)? as usize;
+        let mut header_len = PACKET_HEADER_LEN;
+
+        // The marker signals the actual length follows the header
+        if self.frame.is_extended(length) {
+            header_len += EXTENDED_LEN_HEADER_LEN;
+            if src.len() < header_len {
+                return Ok(None);
+            }
+            let hdr: ExtendedLenHeader = src[PACKET_HEADER_LEN..header_len]
+                .try_into()
+                .expect("Extended length header");
+            length = self.cipher.decode_extended_len(hdr) as usize;
+        }
 
         // Verify the packet is not great than the maximum limit
-        check_packet_len(length)?;
+        self.frame.check_len(length)?;
 
         // Try to read the actual payload
-        let total_len = PACKET_HEADER_LEN + length;
+        let total_len = header_len + length;
 
         //Read data
         if src.len() < total_len {
@@ -79,16 +245,35 @@ impl Decoder for PacketDecodeCodec {
             return Ok(None);
         }
 
-        src.advance(PACKET_HEADER_LEN);
+        src.advance(header_len);
         let mut packet_data = src.split_to(length);
-        self.0.decrypt(packet_data.as_mut().into());
-        let pkt = ShroomPacket::from_data(packet_data.freeze());
+        self.cipher.decrypt(packet_data.as_mut().into());
+        let pkt = ShroomPacket::from_data(packet_data.freeze()).with_str_codec(self.str_codec);
 
         Ok(Some(pkt))
     }
 }
 
-pub struct PacketEncodeCodec(pub ShroomCrypto);
+pub struct PacketEncodeCodec {
+    pub cipher: BoxedPacketCipher,
+    pub frame: FrameConfig,
+}
+
+impl PacketEncodeCodec {
+    /// Encodes all frames onto the buffer, so they can be written at once
+    /// If an error occurs the previous frames stay on the buffer,
+    /// because the cipher state already advanced
+    pub fn encode_all<B: AsRef<[u8]>>(
+        &mut self,
+        items: impl IntoIterator<Item = B>,
+        dst: &mut BytesMut,
+    ) -> NetResult<()> {
+        for item in items {
+            self.encode(item.as_ref(), dst)?;
+        }
+        Ok(())
+    }
+}
 
 impl<'a> Encoder<&'a [u8]> for PacketCodec {
     type Error = NetError;
@@ -103,13 +288,187 @@ impl<'a> Encoder<&'a [u8]> for PacketEncodeCodec {
 
     fn encode(&mut self, item: &'a [u8], dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         let len = item.len();
-        check_packet_len(len)?;
-        dst.reserve(PACKET_HEADER_LEN + len);
+        self.frame.check_len(len)?;
+
+        if self.frame.is_extended(len) {
+            dst.reserve(PACKET_HEADER_LEN + EXTENDED_LEN_HEADER_LEN + len);
+            dst.put_slice(&self.cipher.encode_header(EXTENDED_LEN_MARKER));
+            dst.put_slice(&self.cipher.encode_extended_len(len as u32));
+        } else {
+            dst.reserve(PACKET_HEADER_LEN + len);
+            dst.put_slice(&self.cipher.encode_header(len as u16));
+        }
 
-        dst.put_slice(&self.0.encode_header(len as u16));
+        // Only encrypt the payload which was just written
+        let offset = dst.len();
         dst.put_slice(item);
-        self.0
-            .encrypt((&mut dst[PACKET_HEADER_LEN..PACKET_HEADER_LEN + len]).into());
+        self.cipher.encrypt((&mut dst[offset..offset + len]).into());
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{
+        crypto::{CryptoContext, CryptoProfile, HeaderScheme, PacketCipher, SharedCryptoContext},
+        net::codec::handshake::{HandshakeVersion, LocaleCode},
+        net::codec::{Handshake, MAX_PACKET_LEN},
+        NetError,
+    };
+
+    use super::{FrameConfig, PacketCodec};
+
+    fn server_client_codec(
+        ctx: SharedCryptoContext,
+        version: HandshakeVersion,
+    ) -> (PacketCodec, PacketCodec) {
+        server_client_codec_with_locale(ctx, version, LocaleCode::Global)
+    }
+
+    fn server_client_codec_with_locale(
+        ctx: SharedCryptoContext,
+        version: HandshakeVersion,
+        locale: LocaleCode,
+    ) -> (PacketCodec, PacketCodec) {
+        let handshake = Handshake::new_random(version, locale, rand::thread_rng());
+        (
+            PacketCodec::from_server_handshake(ctx.clone(), handshake.clone()),
+            PacketCodec::from_client_handshake(ctx, handshake),
+        )
+    }
+
+    fn check_server_to_client(server: &mut PacketCodec, client: &mut PacketCodec) {
+        let mut buf = BytesMut::new();
+        for data in [&[1u8, 2, 3][..], &[], &[0xFF; 2048]] {
+            server.encode(data, &mut buf).unwrap();
+            let pkt = client.decode(&mut buf).unwrap().expect("packet");
+            assert_eq!(pkt.as_ref(), data);
+        }
+    }
+
+    #[test]
+    fn server_to_client() {
+        for (v, locale, profile) in [
+            (
+                HandshakeVersion::v83(),
+                LocaleCode::Global,
+                CryptoProfile::LEGACY,
+            ),
+            (
+                HandshakeVersion::must_parse(176, "1"),
+                LocaleCode::Global,
+                CryptoProfile::AES_ONLY,
+            ),
+            (
+                HandshakeVersion::v83(),
+                LocaleCode::Other(0x42),
+                CryptoProfile::EXTENDED,
+            ),
+        ] {
+            let (mut server, mut client) =
+                server_client_codec_with_locale(SharedCryptoContext::default(), v, locale);
+            assert_eq!(server.encode.cipher.profile(), Some(profile));
+            assert_eq!(
+                server.frame_config(),
+                FrameConfig::from_header_scheme(profile.header)
+            );
+            check_server_to_client(&mut server, &mut client);
+        }
+    }
+
+    #[test]
+    fn server_to_client_plaintext() {
+        let ctx = SharedCryptoContext::new(CryptoContext {
+            plaintext: true,
+            ..Default::default()
+        });
+        let (mut server, mut client) = server_client_codec(ctx, HandshakeVersion::v83());
+        check_server_to_client(&mut server, &mut client);
+
+        // Payload is not encrypted
+        let mut buf = BytesMut::new();
+        server.encode(&[1u8, 2, 3][..], &mut buf).unwrap();
+        assert_eq!(&buf[4..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn encode_all() {
+        let (mut server, mut client) =
+            server_client_codec(SharedCryptoContext::default(), HandshakeVersion::v83());
+        let data = [&[1u8, 2, 3][..], &[], &[0xFF; 2048]];
+
+        let mut buf = BytesMut::new();
+        server.encode_all(data, &mut buf).unwrap();
+        for data in data {
+            let pkt = client.decode(&mut buf).unwrap().expect("packet");
+            assert_eq!(pkt.as_ref(), data);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn extended_len() {
+        let ctx = SharedCryptoContext::new(CryptoContext {
+            profile: Some(CryptoProfile {
+                header: HeaderScheme::Extended,
+                ..CryptoProfile::LEGACY
+            }),
+            ..Default::default()
+        });
+        let (mut server, mut client) = server_client_codec(ctx, HandshakeVersion::v83());
+        check_server_to_client(&mut server, &mut client);
+
+        let mut buf = BytesMut::new();
+        for len in [u16::MAX as usize - 1, u16::MAX as usize, 100_000] {
+            let data = vec![0xAB; len];
+            server.encode(data.as_slice(), &mut buf).unwrap();
+            // Partial frames wait for more data
+            let mut partial = buf.split_to(6);
+            assert!(client.decode(&mut partial).unwrap().is_none());
+            partial.unsplit(buf);
+            buf = partial;
+
+            let pkt = client.decode(&mut buf).unwrap().expect("packet");
+            assert_eq!(pkt.as_ref(), data.as_slice());
+        }
+    }
+
+    #[test]
+    fn frame_limits() {
+        let (server, client) =
+            server_client_codec(SharedCryptoContext::default(), HandshakeVersion::v83());
+        let (mut server, mut client) = (
+            server.with_frame_config(
+                FrameConfig::default().with_max_frame_len(u16::MAX as usize + 1),
+            ),
+            client.with_frame_config(FrameConfig::default().with_max_frame_len(16)),
+        );
+
+        let mut buf = BytesMut::new();
+        let data = vec![0; u16::MAX as usize + 1];
+        assert!(matches!(
+            server.encode(data.as_slice(), &mut buf),
+            Err(NetError::HeaderLenLimit(_))
+        ));
+
+        server.encode(&[0; 17][..], &mut buf).unwrap();
+        assert!(matches!(
+            client.decode(&mut buf),
+            Err(NetError::MaxFrameLen { len: 17, max: 16 })
+        ));
+
+        let (mut server, _) =
+            server_client_codec(SharedCryptoContext::default(), HandshakeVersion::v83());
+        let data = vec![0; MAX_PACKET_LEN + 1];
+        assert!(matches!(
+            server.encode(data.as_slice(), &mut buf),
+            Err(NetError::MaxFrameLen {
+                max: MAX_PACKET_LEN,
+                ..
+            })
+        ));
+    }
+}
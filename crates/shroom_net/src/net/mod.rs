@@ -1,5 +1,9 @@
+pub mod blocking;
 pub mod codec;
 pub mod service;
 pub mod session;
+pub mod split;
 
-pub use session::{SessionTransport, ShroomSession};
+pub use blocking::BlockingShroomSession;
+pub use session::{PeerAddr, SessionTransport, ShroomSession};
+pub use split::{ShroomSessionReader, ShroomSessionWriter};
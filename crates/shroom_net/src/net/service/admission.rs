@@ -0,0 +1,366 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Pruning the rate windows after this many tracked addresses
+const PRUNE_RATE_WINDOWS_LEN: usize = 1024;
+
+/// Filter which decides If a connection from the address is accepted,
+/// the address is `None` If the transport doesn't provide one
+pub trait AcceptFilter: Send + Sync {
+    fn accept(&self, addr: Option<SocketAddr>) -> bool;
+}
+
+impl<F> AcceptFilter for F
+where
+    F: Fn(Option<SocketAddr>) -> bool + Send + Sync,
+{
+    fn accept(&self, addr: Option<SocketAddr>) -> bool {
+        self(addr)
+    }
+}
+
+/// Basic filter with banned IPs and an optional allowlist,
+/// connections without an address are rejected, because they can't be checked
+#[derive(Debug, Default, Clone)]
+pub struct IpFilter {
+    pub banned: HashSet<IpAddr>,
+    /// If set only these IPs are accepted
+    pub allowed: Option<HashSet<IpAddr>>,
+}
+
+impl AcceptFilter for IpFilter {
+    fn accept(&self, addr: Option<SocketAddr>) -> bool {
+        let Some(ip) = addr.map(|addr| addr.ip()) else {
+            return false;
+        };
+        !self.banned.contains(&ip) && self.allowed.as_ref().is_none_or(|a| a.contains(&ip))
+    }
+}
+
+/// Max number of connects within the interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRate {
+    pub max: usize,
+    pub interval: Duration,
+}
+
+/// Limits for incoming connections, `None` means unlimited
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionLimits {
+    /// Max active connections
+    pub max_connections: Option<usize>,
+    /// Max active connections per IP
+    pub max_connections_per_ip: Option<usize>,
+    /// Max connects of all IPs
+    pub connect_rate: Option<ConnectRate>,
+    /// Max connects per IP
+    pub connect_rate_per_ip: Option<ConnectRate>,
+}
+
+/// Reason why a connection was rejected
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    #[error("Rejected by the accept filter")]
+    Filtered,
+    #[error("Max connections reached")]
+    MaxConnections,
+    #[error("Max connections per IP reached")]
+    MaxConnectionsPerIp,
+    #[error("Connect rate exceeded")]
+    ConnectRate,
+    #[error("Connect rate per IP exceeded")]
+    ConnectRatePerIp,
+    #[error("Unknown peer address, which is required by the per IP limits")]
+    UnknownAddress,
+}
+
+/// Fixed window to count the connects
+#[derive(Debug, Clone, Copy)]
+struct RateWindow {
+    start: Instant,
+    count: usize,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            count: 0,
+        }
+    }
+
+    fn is_expired(&self, rate: &ConnectRate, now: Instant) -> bool {
+        now.duration_since(self.start) >= rate.interval
+    }
+
+    /// Checks If another connect is within the rate
+    fn check(&mut self, rate: &ConnectRate, now: Instant) -> bool {
+        if self.is_expired(rate, now) {
+            *self = Self::new(now);
+        }
+        self.count < rate.max
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+type SharedConnectionCounts = Arc<parking_lot::Mutex<ConnectionCounts>>;
+
+/// Guard of an admitted connection, which releases the connection slot on drop
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counts: SharedConnectionCounts,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(n) = counts.per_ip.get_mut(&ip) {
+                *n -= 1;
+                if *n == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Admission control for the connections of a server
+pub struct Admission {
+    limits: ConnectionLimits,
+    filter: Option<Arc<dyn AcceptFilter>>,
+    counts: SharedConnectionCounts,
+    rate: Option<RateWindow>,
+    rate_per_ip: HashMap<IpAddr, RateWindow>,
+}
+
+impl Debug for Admission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Admission")
+            .field("limits", &self.limits)
+            .field("filter", &self.filter.is_some())
+            .field("counts", &self.counts)
+            .finish()
+    }
+}
+
+impl Admission {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            filter: None,
+            counts: SharedConnectionCounts::default(),
+            rate: None,
+            rate_per_ip: HashMap::new(),
+        }
+    }
+
+    /// Sets the accept filter
+    pub fn set_filter(&mut self, filter: impl AcceptFilter + 'static) {
+        self.filter = Some(Arc::new(filter));
+    }
+
+    /// Number of active connections
+    pub fn active_connections(&self) -> usize {
+        self.counts.lock().total
+    }
+
+    /// Checks the limits for a new connection, a connection without an address
+    /// is rejected If a per IP limit is set. The connection counts until the guard is dropped
+    pub fn admit(&mut self, addr: Option<SocketAddr>) -> Result<ConnectionGuard, RejectReason> {
+        let now = Instant::now();
+        let ip = addr.map(|addr| addr.ip());
+
+        if let Some(filter) = &self.filter {
+            if !filter.accept(addr) {
+                return Err(RejectReason::Filtered);
+            }
+        }
+
+        let mut counts = self.counts.lock();
+        if self
+            .limits
+            .max_connections
+            .is_some_and(|max| counts.total >= max)
+        {
+            return Err(RejectReason::MaxConnections);
+        }
+
+        if let Some(max) = self.limits.max_connections_per_ip {
+            let ip = ip.ok_or(RejectReason::UnknownAddress)?;
+            if counts.per_ip.get(&ip).copied().unwrap_or(0) >= max {
+                return Err(RejectReason::MaxConnectionsPerIp);
+            }
+        }
+
+        // Check both rates, before counting the connect
+        if let Some(rate) = &self.limits.connect_rate {
+            let window = self.rate.get_or_insert_with(|| RateWindow::new(now));
+            if !window.check(rate, now) {
+                return Err(RejectReason::ConnectRate);
+            }
+        }
+
+        if let Some(rate) = &self.limits.connect_rate_per_ip {
+            let ip = ip.ok_or(RejectReason::UnknownAddress)?;
+            if self.rate_per_ip.len() >= PRUNE_RATE_WINDOWS_LEN {
+                self.rate_per_ip.retain(|_, w| !w.is_expired(rate, now));
+            }
+
+            let window = self
+                .rate_per_ip
+                .entry(ip)
+                .or_insert_with(|| RateWindow::new(now));
+            if !window.check(rate, now) {
+                return Err(RejectReason::ConnectRatePerIp);
+            }
+            window.count += 1;
+        }
+
+        if let Some(window) = self.rate.as_mut() {
+            window.count += 1;
+        }
+
+        counts.total += 1;
+        if let Some(ip) = ip {
+            *counts.per_ip.entry(ip).or_default() += 1;
+        }
+
+        Ok(ConnectionGuard {
+            counts: self.counts.clone(),
+            ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{Admission, ConnectRate, ConnectionLimits, IpFilter, RejectReason};
+
+    fn addr(ip: [u8; 4]) -> Option<SocketAddr> {
+        Some(SocketAddr::from((ip, 1234)))
+    }
+
+    #[test]
+    fn connection_caps() {
+        let mut admission = Admission::new(ConnectionLimits {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+
+        let a = admission.admit(addr([1, 1, 1, 1])).unwrap();
+        let _b = admission.admit(addr([1, 1, 1, 1])).unwrap();
+        assert_eq!(
+            admission.admit(addr([1, 1, 1, 1])).unwrap_err(),
+            RejectReason::MaxConnectionsPerIp
+        );
+        let _c = admission.admit(addr([2, 2, 2, 2])).unwrap();
+        assert_eq!(
+            admission.admit(None).unwrap_err(),
+            RejectReason::MaxConnections
+        );
+
+        // Dropping the guard frees the slot
+        drop(a);
+        assert_eq!(admission.active_connections(), 2);
+        let _a = admission.admit(addr([1, 1, 1, 1])).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_rate() {
+        let rate = ConnectRate {
+            max: 2,
+            interval: Duration::from_secs(10),
+        };
+        let mut admission = Admission::new(ConnectionLimits {
+            connect_rate_per_ip: Some(rate),
+            connect_rate: Some(ConnectRate { max: 3, ..rate }),
+            ..Default::default()
+        });
+
+        let _a = admission.admit(addr([1, 1, 1, 1])).unwrap();
+        let _b = admission.admit(addr([1, 1, 1, 1])).unwrap();
+        assert_eq!(
+            admission.admit(addr([1, 1, 1, 1])).unwrap_err(),
+            RejectReason::ConnectRatePerIp
+        );
+        let _c = admission.admit(addr([2, 2, 2, 2])).unwrap();
+        assert_eq!(
+            admission.admit(addr([3, 3, 3, 3])).unwrap_err(),
+            RejectReason::ConnectRate
+        );
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let _d = admission.admit(addr([1, 1, 1, 1])).unwrap();
+    }
+
+    #[test]
+    fn filter() {
+        let mut admission = Admission::new(ConnectionLimits::default());
+        admission.set_filter(IpFilter {
+            banned: [[1, 1, 1, 1].into()].into(),
+            ..Default::default()
+        });
+        assert_eq!(
+            admission.admit(addr([1, 1, 1, 1])).unwrap_err(),
+            RejectReason::Filtered
+        );
+        assert!(admission.admit(addr([2, 2, 2, 2])).is_ok());
+
+        admission.set_filter(|addr: Option<SocketAddr>| addr.is_some_and(|a| a.port() != 1234));
+        assert!(admission.admit(addr([2, 2, 2, 2])).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_peer_addr() {
+        // The filter decides for connections without an address
+        let mut admission = Admission::new(ConnectionLimits::default());
+        assert!(admission.admit(None).is_ok());
+        admission.set_filter(IpFilter::default());
+        assert_eq!(admission.admit(None).unwrap_err(), RejectReason::Filtered);
+        admission.set_filter(|addr: Option<SocketAddr>| addr.is_none());
+        assert!(admission.admit(None).is_ok());
+
+        // Per IP limits can't be checked, so the connection is rejected
+        let rate = ConnectRate {
+            max: 2,
+            interval: Duration::from_secs(10),
+        };
+        for limits in [
+            ConnectionLimits {
+                max_connections_per_ip: Some(2),
+                ..Default::default()
+            },
+            ConnectionLimits {
+                connect_rate_per_ip: Some(rate),
+                ..Default::default()
+            },
+        ] {
+            let mut admission = Admission::new(limits);
+            assert_eq!(
+                admission.admit(None).unwrap_err(),
+                RejectReason::UnknownAddress
+            );
+            assert_eq!(admission.active_connections(), 0);
+            assert!(admission.admit(addr([1, 1, 1, 1])).is_ok());
+        }
+    }
+}
@@ -1,16 +1,56 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
 
 use async_trait::async_trait;
 use futures::{future, Future};
 
 use crate::{
-    net::{SessionTransport, ShroomSession},
+    net::{codec::handshake::Handshake, SessionTransport, ShroomSession},
     DecodePacket, NetError, PacketReader, ShroomPacket,
 };
 
-use super::{SessionHandleResult, ShroomContext, SharedSessionHandle};
+use super::{
+    admission::RejectReason, latency::LatencyStats, DisconnectKind, DisconnectReason,
+    SessionHandleResult, SharedSessionHandle, ShroomContext,
+};
+
+/// How a connection ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionOutcome {
+    /// The connection didn't end yet
+    #[default]
+    Pending,
+    /// Rejected by the connection limits or the accept filter, before the handshake
+    RejectedByLimits(RejectReason),
+    /// Rejected by `MakeServerSessionHandler::on_accept`
+    RejectedByHandler,
+    /// The handshake couldn't be sent
+    HandshakeError,
+    /// `MakeServerSessionHandler::make_handler` failed
+    MakeHandlerError,
+    /// The session was executed and ended with the reason
+    Disconnected(DisconnectKind),
+}
+
+/// Stats of a connection, which are passed to `MakeServerSessionHandler::on_session_ended`
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    pub peer_addr: Option<SocketAddr>,
+    /// How the connection ended
+    pub outcome: SessionOutcome,
+    /// Whether the handler was created and the session was executed
+    pub started: bool,
+    /// Duration since the connection was accepted
+    pub duration: Duration,
+    /// Number of packets read from the client
+    pub packets_read: u64,
+    pub latency: LatencyStats,
+    pub migrated: bool,
+}
 
-/// Handler creator
+/// Handler creator, the `on_*` hooks are called by `ShroomServer` in the order:
+/// `on_accept`, `on_handshake_sent`, `on_session_started` and `on_session_ended`,
+/// `on_session_ended` is called for every connection even If It ended before the session started.
+/// Connections rejected by the connection limits skip `on_accept` and only call `on_session_ended`
 #[async_trait]
 pub trait MakeServerSessionHandler {
     type Transport: SessionTransport;
@@ -24,13 +64,27 @@ pub trait MakeServerSessionHandler {
         sess: ShroomSession<Self::Transport>,
         handle: SharedSessionHandle,
     ) -> Result<ShroomContext<Self::Handler>, Self::Error>;
+
+    /// Called before the handshake is sent, returning false rejects the connection
+    async fn on_accept(&mut self, _peer_addr: Option<SocketAddr>) -> bool {
+        true
+    }
+
+    /// Called after the handshake was sent
+    async fn on_handshake_sent(&mut self, _handshake: &Handshake) {}
+
+    /// Called after the handler was created, before the session is executed
+    async fn on_session_started(&mut self, _handle: &SharedSessionHandle) {}
+
+    /// Called once the connection is closed, `SessionStats::outcome` tells how It ended
+    async fn on_session_ended(&mut self, _stats: SessionStats) {}
 }
 
 /// Session handler trait, which used to handle packets and handle messages
 #[async_trait]
 pub trait ShroomSessionHandler: Sized {
     type Transport: SessionTransport + Send;
-    type Error: From<NetError> + Debug;
+    type Error: From<NetError> + Debug + Send;
     type Msg: Send;
 
     /// Handle an incoming packet
@@ -49,19 +103,20 @@ pub trait ShroomSessionHandler: Sized {
         unreachable!()
     }
 
-    async fn finish(self, _is_migrating: bool) -> Result<(), Self::Error> {
+    /// Called once the session ended with the `reason`
+    async fn finish(self, _reason: DisconnectReason<Self::Error>) -> Result<(), Self::Error> {
         Ok(())
     }
 }
 
 /// Call a the specified handler function `f` and process the returned response
-pub async fn call_handler_fn<'session, F, Req, Fut, Err, H: ShroomSessionHandler>(
+pub async fn call_handler_fn<'session, F, Req, Fut, Err, H>(
     ctx: &'session mut ShroomContext<H>,
     mut pr: PacketReader<'session>,
     mut f_handler: F,
 ) -> Result<(), Err>
 where
-    H: 'session,
+    H: ShroomSessionHandler + 'session,
     Req: DecodePacket<'session>,
     Err: From<NetError>,
     Fut: Future<Output = Result<(), Err>>,
@@ -73,7 +128,9 @@ where
 
 /// Declares an async router fn
 /// which routes the packet to the matching handler
-/// by reading the Opcode and checking It against the `OPCODE` from the `HasOpcode` Trait
+/// by reading the Opcode and checking It against the `OPCODE` from the `HasOpcode` Trait,
+/// packets which are not allowed by the phase guard of the context are not routed.
+/// `router::Router` can be built at runtime, merged across modules and detects duplicate opcodes
 /// Example:
 ///
 /// shroom_router_fn!(
@@ -89,6 +146,9 @@ macro_rules! shroom_router_fn {
     ($fname:ident, $handler:ty, $err:ty, $default_handler:expr, $($req:ty => $handler_fn:expr),* $(,)?) => {
         async fn $fname<'session>(ctx: &'session mut ShroomContext<$handler>, mut pr: $crate::PacketReader<'session>) ->  Result<(), $err> {
             let recv_op = pr.read_opcode()?;
+            if !ctx.check_opcode(recv_op)? {
+                return Ok(());
+            }
             match recv_op {
                 $(
                     <$req as $crate::HasOpcode>::OPCODE  => $crate::net::service::handler::call_handler_fn(ctx, pr, $handler_fn).await,
@@ -105,15 +165,19 @@ mod tests {
         crypto::SharedCryptoContext,
         net::{
             service::{
-                BasicHandshakeGenerator, HandshakeGenerator, SessionHandleResult, ShroomContext, SharedSessionHandle,
+                BasicHandshakeGenerator, HandshakeGenerator, SessionHandleResult,
+                SharedSessionHandle, ShroomContext,
             },
             ShroomSession,
         },
         opcode::WithOpcode,
-        PacketReader, PacketWriter, ShroomPacket,
+        HasOpcode, NetError, PacketReader, PacketWriter, ShroomPacket,
     };
 
-    use super::ShroomSessionHandler;
+    use super::{
+        super::phase::{PhaseGuard, SessionPhase},
+        ShroomSessionHandler,
+    };
 
     pub type Req1 = WithOpcode<0, u16>;
     pub type Req2 = WithOpcode<1, ()>;
@@ -131,7 +195,7 @@ mod tests {
         }
 
         async fn handle_double(ctx: &mut Ctx, _req: WithOpcode<1, ()>) -> anyhow::Result<()> {
-            Ok(ctx.send(WithOpcode::<1, u16>(ctx.state.req1.0 * 2)).await?)
+            ctx.send(WithOpcode::<1, u16>(ctx.state.req1.0 * 2)).await
         }
 
         async fn handle_default(
@@ -202,4 +266,41 @@ mod tests {
 
         handle(&mut ctx, pkt_req2.into_reader()).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn router_phase_guard() {
+        let mut pw = PacketWriter::default();
+        pw.write_opcode(1u16).expect("Encode");
+        let pkt_req2 = pw.into_packet();
+
+        shroom_router_fn!(
+            handle,
+            Handler,
+            anyhow::Error,
+            Handler::handle_default,
+            Req1 => Handler::handle_req1,
+            Req2 => Handler::handle_double,
+        );
+
+        let session_handle = SharedSessionHandle::new();
+        let mut ctx = ShroomContext::new(get_fake_session(), Handler::default(), session_handle.0);
+
+        // Req2 is only allowed after the login
+        ctx.set_phase_guard(
+            PhaseGuard::new(SessionPhase::LoggedOut)
+                .allow(SessionPhase::LoggedOut, [Req1::OPCODE])
+                .allow(SessionPhase::LoggedIn, [Req1::OPCODE, Req2::OPCODE]),
+        );
+        let err = handle(&mut ctx, pkt_req2.into_reader()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast().unwrap(),
+            NetError::UnexpectedOpcode {
+                phase: SessionPhase::LoggedOut,
+                opcode: 1
+            }
+        ));
+
+        ctx.set_phase(SessionPhase::LoggedIn);
+        handle(&mut ctx, pkt_req2.into_reader()).await.unwrap();
+    }
 }
@@ -17,7 +17,7 @@ pub struct BasicHandshakeGenerator {
 }
 
 impl BasicHandshakeGenerator {
-    /// Create a new handshake generator, will panic if subversion is larger than 2
+    /// Create a new handshake generator, the locale might be a custom `LocaleCode::Other`
     pub fn new(version: HandshakeVersion, locale: LocaleCode) -> Self {
         Self { version, locale }
     }
@@ -37,6 +37,6 @@ impl HandshakeGenerator for BasicHandshakeGenerator {
     fn generate_handshake(&self) -> Handshake {
         // Using thread_rng to generate the round keys
         let rng = rand::thread_rng();
-        Handshake::new_random(self.version.clone(), self.locale, rng)
+        Handshake::new_random(self.version, self.locale, rng)
     }
 }
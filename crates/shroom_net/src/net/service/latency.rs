@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Weight of a new sample for the smoothed RTT (RFC 6298)
+const SRTT_ALPHA: f64 = 1.0 / 8.0;
+/// Weight of a new sample for the jitter (RFC 3550)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Latency stats of a session, which are measured with the ping/pong cycle
+#[derive(Debug, Default, Clone)]
+pub struct LatencyStats {
+    pending_ping: Option<Instant>,
+    last_rtt: Option<Duration>,
+    srtt: Option<Duration>,
+    jitter: Duration,
+    samples: u64,
+}
+
+impl LatencyStats {
+    /// Whether a ping was sent, which was not answered yet
+    pub fn is_ping_pending(&self) -> bool {
+        self.pending_ping.is_some()
+    }
+
+    /// RTT of the last answered ping
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    /// Smoothed RTT
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Smoothed variation between consecutive RTTs
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Number of measured RTTs
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Marks a ping as sent at `now`
+    pub(crate) fn ping_sent(&mut self, now: Instant) {
+        self.pending_ping = Some(now);
+    }
+
+    /// Records the pong for the pending ping and returns the RTT,
+    /// a pong without pending ping is ignored
+    pub(crate) fn pong_received(&mut self, now: Instant) -> Option<Duration> {
+        let rtt = now.duration_since(self.pending_ping.take()?);
+
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => srtt.mul_f64(1.0 - SRTT_ALPHA) + rtt.mul_f64(SRTT_ALPHA),
+            None => rtt,
+        });
+
+        if let Some(last) = self.last_rtt {
+            let diff = rtt.abs_diff(last).as_secs_f64();
+            let jitter = self.jitter.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(jitter + (diff - jitter) * JITTER_GAIN);
+        }
+
+        self.last_rtt = Some(rtt);
+        self.samples += 1;
+        Some(rtt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::LatencyStats;
+
+    #[test]
+    fn rtt() {
+        let mut stats = LatencyStats::default();
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        // No pending ping
+        assert_eq!(stats.pong_received(now), None);
+
+        stats.ping_sent(now);
+        assert!(stats.is_ping_pending());
+        assert_eq!(stats.pong_received(now + ms(100)), Some(ms(100)));
+        assert!(!stats.is_ping_pending());
+        assert_eq!(stats.smoothed_rtt(), Some(ms(100)));
+        assert_eq!(stats.jitter(), Duration::ZERO);
+
+        stats.ping_sent(now);
+        assert_eq!(stats.pong_received(now + ms(260)), Some(ms(260)));
+        assert_eq!(stats.smoothed_rtt(), Some(ms(120)));
+        assert_eq!(stats.jitter(), ms(10));
+        assert_eq!(stats.samples(), 2);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{any::Any, collections::HashMap, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use tokio::time::Instant;
+
+use crate::{NetError, ShroomPacket};
+
+use super::{handler::ShroomSessionHandler, SessionHandleResult, ShroomContext};
+
+/// Middleware, which wraps `ShroomSessionHandler::handle_packet` and thereby the router.
+/// Middlewares are added to the context with `ShroomContext::layer`, the first one is the outermost
+#[async_trait]
+pub trait PacketMiddleware<H: ShroomSessionHandler + Send>: Send + Sync {
+    /// Handles the packet, calling `next.run` passes It to the next middleware or the handler
+    async fn handle(
+        &self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+        next: Next<'_, H>,
+    ) -> Result<SessionHandleResult, H::Error>;
+}
+
+pub(crate) type MiddlewareStack<H> = Arc<Vec<Arc<dyn PacketMiddleware<H>>>>;
+
+/// The remaining middlewares and the handler
+pub struct Next<'a, H: ShroomSessionHandler + Send> {
+    middleware: &'a [Arc<dyn PacketMiddleware<H>>],
+}
+
+impl<'a, H: ShroomSessionHandler + Send> Next<'a, H> {
+    pub(crate) fn new(middleware: &'a [Arc<dyn PacketMiddleware<H>>]) -> Self {
+        Self { middleware }
+    }
+
+    /// Passes the packet to the next middleware or the handler
+    pub async fn run(
+        self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+    ) -> Result<SessionHandleResult, H::Error> {
+        match self.middleware.split_first() {
+            Some((mw, rest)) => mw.handle(ctx, packet, Next::new(rest)).await,
+            None => H::handle_packet(ctx, packet).await,
+        }
+    }
+}
+
+/// Logs every packet with the opcode, the result and the handle time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogLayer;
+
+#[async_trait]
+impl<H: ShroomSessionHandler + Send> PacketMiddleware<H> for LogLayer {
+    async fn handle(
+        &self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+        next: Next<'_, H>,
+    ) -> Result<SessionHandleResult, H::Error> {
+        let opcode = packet.read_opcode().ok();
+        let start = Instant::now();
+        let res = next.run(ctx, packet).await;
+        match &res {
+            Ok(_) => log::trace!("Handled opcode {opcode:X?} in {:?}", start.elapsed()),
+            Err(err) => log::debug!("Failed to handle opcode {opcode:X?}: {err:?}"),
+        }
+        res
+    }
+}
+
+/// Converts a panic of the handler into `NetError::HandlerPanic`,
+/// the state of the handler might be inconsistent after a panic
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CatchPanicLayer;
+
+fn panic_msg(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[async_trait]
+impl<H: ShroomSessionHandler + Send> PacketMiddleware<H> for CatchPanicLayer {
+    async fn handle(
+        &self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+        next: Next<'_, H>,
+    ) -> Result<SessionHandleResult, H::Error> {
+        match AssertUnwindSafe(next.run(ctx, packet)).catch_unwind().await {
+            Ok(res) => res,
+            Err(panic) => Err(NetError::HandlerPanic(panic_msg(panic.as_ref())).into()),
+        }
+    }
+}
+
+/// Guard, which only passes packets to the handler If the predicate returns true,
+/// otherwise the packet is rejected with `NetError::Rejected`
+pub struct GuardLayer<F>(pub F);
+
+#[async_trait]
+impl<H, F> PacketMiddleware<H> for GuardLayer<F>
+where
+    H: ShroomSessionHandler + Send + Sync,
+    F: Fn(&H, u16) -> bool + Send + Sync,
+{
+    async fn handle(
+        &self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+        next: Next<'_, H>,
+    ) -> Result<SessionHandleResult, H::Error> {
+        let opcode = packet.read_opcode()?;
+        if !(self.0)(&ctx.state, opcode) {
+            return Err(NetError::Rejected(opcode).into());
+        }
+        next.run(ctx, packet).await
+    }
+}
+
+/// Stats of an opcode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodeStats {
+    pub count: u64,
+    pub errors: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+/// Collects the `OpcodeStats` per opcode, clones share the same stats
+/// so they can be used as layer for every session
+#[derive(Debug, Default, Clone)]
+pub struct OpcodeMetrics(Arc<parking_lot::Mutex<HashMap<u16, OpcodeStats>>>);
+
+impl OpcodeMetrics {
+    pub fn get(&self, opcode: u16) -> Option<OpcodeStats> {
+        self.0.lock().get(&opcode).copied()
+    }
+
+    /// Copy of the stats of all opcodes
+    pub fn snapshot(&self) -> HashMap<u16, OpcodeStats> {
+        self.0.lock().clone()
+    }
+}
+
+#[async_trait]
+impl<H: ShroomSessionHandler + Send> PacketMiddleware<H> for OpcodeMetrics {
+    async fn handle(
+        &self,
+        ctx: &mut ShroomContext<H>,
+        packet: ShroomPacket,
+        next: Next<'_, H>,
+    ) -> Result<SessionHandleResult, H::Error> {
+        let Ok(opcode) = packet.read_opcode() else {
+            return next.run(ctx, packet).await;
+        };
+
+        let start = Instant::now();
+        let res = next.run(ctx, packet).await;
+        let elapsed = start.elapsed();
+
+        let mut metrics = self.0.lock();
+        let stats = metrics.entry(opcode).or_default();
+        stats.count += 1;
+        stats.errors += res.is_err() as u64;
+        stats.total_time += elapsed;
+        stats.max_time = stats.max_time.max(elapsed);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::{
+        crypto::SharedCryptoContext,
+        net::{
+            service::{
+                BasicHandshakeGenerator, HandshakeGenerator, SessionHandleResult,
+                SharedSessionHandle, ShroomContext,
+            },
+            ShroomSession,
+        },
+        NetError, PacketWriter, ShroomPacket,
+    };
+
+    use super::{
+        CatchPanicLayer, GuardLayer, Next, OpcodeMetrics, PacketMiddleware, ShroomSessionHandler,
+    };
+
+    #[derive(Debug, Default)]
+    struct Handler {
+        logged_in: bool,
+        trace: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl ShroomSessionHandler for Handler {
+        type Transport = std::io::Cursor<Vec<u8>>;
+        type Error = anyhow::Error;
+        type Msg = ();
+
+        async fn handle_packet(
+            ctx: &mut ShroomContext<Self>,
+            packet: ShroomPacket,
+        ) -> Result<SessionHandleResult, Self::Error> {
+            ctx.trace.push("handler");
+            match packet.read_opcode()? {
+                1 => panic!("handler panic"),
+                2 => Err(anyhow::format_err!("handler error")),
+                _ => Ok(SessionHandleResult::Ok),
+            }
+        }
+
+        async fn handle_msg(
+            _ctx: &mut ShroomContext<Self>,
+            _msg: Self::Msg,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    struct TraceLayer(&'static str);
+
+    #[async_trait]
+    impl PacketMiddleware<Handler> for TraceLayer {
+        async fn handle(
+            &self,
+            ctx: &mut ShroomContext<Handler>,
+            packet: ShroomPacket,
+            next: Next<'_, Handler>,
+        ) -> anyhow::Result<SessionHandleResult> {
+            ctx.trace.push(self.0);
+            next.run(ctx, packet).await
+        }
+    }
+
+    fn pkt(op: u16) -> ShroomPacket {
+        let mut pw = PacketWriter::default();
+        pw.write_opcode(op).expect("Encode");
+        pw.into_packet()
+    }
+
+    #[tokio::test]
+    async fn middleware() {
+        let hshake = BasicHandshakeGenerator::v83().generate_handshake();
+        let sess = ShroomSession::from_client_handshake(
+            std::io::Cursor::new(vec![]),
+            SharedCryptoContext::default(),
+            hshake,
+        );
+        let mut ctx = ShroomContext::new(sess, Handler::default(), SharedSessionHandle::new().0);
+
+        let metrics = OpcodeMetrics::default();
+        ctx.layer(metrics.clone());
+        ctx.layer(CatchPanicLayer);
+        ctx.layer(TraceLayer("outer"));
+        ctx.layer(GuardLayer(|h: &Handler, op| h.logged_in || op == 0));
+        ctx.layer(TraceLayer("inner"));
+
+        ctx.handle_packet(pkt(0)).await.unwrap();
+        assert_eq!(ctx.trace, ["outer", "inner", "handler"]);
+
+        // Rejected by the guard
+        let err = ctx.handle_packet(pkt(2)).await.unwrap_err();
+        assert!(matches!(err.downcast().unwrap(), NetError::Rejected(2)));
+
+        ctx.logged_in = true;
+        assert!(ctx.handle_packet(pkt(2)).await.is_err());
+        let err = ctx.handle_packet(pkt(1)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast().unwrap(),
+            NetError::HandlerPanic(msg) if msg == "handler panic"
+        ));
+
+        assert_eq!(metrics.get(0).unwrap().count, 1);
+        let stats = metrics.get(2).unwrap();
+        assert_eq!((stats.count, stats.errors), (2, 2));
+        assert_eq!(metrics.get(1).unwrap().errors, 1);
+    }
+}
@@ -1,22 +1,74 @@
+pub mod admission;
 pub mod handler;
 pub mod handshake_gen;
+pub mod latency;
+pub mod middleware;
+pub mod phase;
+pub mod rate_limit;
+pub mod request;
 pub mod resp;
+pub mod router;
 pub mod server_sess;
 pub mod session_set;
 
 pub use handshake_gen::*;
+use bytes::Bytes;
 use tokio_util::sync::CancellationToken;
-use std::{time::Duration, ops::{DerefMut, Deref}};
+use std::{collections::VecDeque, io, sync::{Arc, OnceLock}, time::Duration, ops::{DerefMut, Deref}};
 
-use crate::{EncodePacket, HasOpcode, util::framed_pipe::{FramedPipeSender, self, FramedPipeReceiver}, PacketBuffer};
+use crate::{packet::{DecodePacketOwned, StringCodec}, EncodePacket, HasOpcode, NetError, ShroomPacket, util::priority_pipe::{priority_pipe, LaneConfig, Priority, PriorityReceiver, PrioritySender}, PacketBuffer};
 
-use self::{handler::ShroomSessionHandler, resp::{IntoResponse, Response}};
+use self::{handler::ShroomSessionHandler, latency::LatencyStats, middleware::{MiddlewareStack, Next, PacketMiddleware}, phase::{PhaseGuard, SessionPhase}, request::{PendingRequests, ResponseFuture, MAX_DEFERRED_PACKETS}, resp::{IntoResponse, Response}};
 
 use super::ShroomSession;
 
 pub const DEFAULT_MIGRATE_DELAY: Duration = Duration::from_millis(7500);
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_PIPE_BUF_CAP: usize = 8 * 1024;
+pub const DEFAULT_PIPE_FRAME_CAP: usize = 128;
+
+/// Policy for frames of the `SharedSessionHandle`, which were dropped because the pipe was full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MissedFramePolicy {
+    /// Closes the session with `NetError::MissedFrame`
+    #[default]
+    Disconnect,
+    /// Logs the missed frames and keeps sending the following frames
+    LogAndContinue,
+}
+
+/// Config for the pipe of the `SharedSessionHandle`, which has a lane per `Priority`.
+/// A lane allocates Its buffer once frames are sent, so idle sessions don't hold `buf_cap` bytes per lane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeConfig {
+    pub high: LaneConfig,
+    pub normal: LaneConfig,
+    /// Lossy per default
+    pub low: LaneConfig,
+    /// Policy for the lanes, which are not lossy
+    pub missed_frame_policy: MissedFramePolicy,
+}
+
+impl PipeConfig {
+    /// Config with the same caps for every lane, the low lane is lossy
+    pub fn new(buf_cap: usize, frame_cap: usize) -> Self {
+        Self {
+            high: LaneConfig::new(buf_cap, frame_cap),
+            normal: LaneConfig::new(buf_cap, frame_cap),
+            low: LaneConfig::lossy(buf_cap, frame_cap),
+            missed_frame_policy: MissedFramePolicy::default(),
+        }
+    }
+}
+
+impl Default for PipeConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_PIPE_BUF_CAP, DEFAULT_PIPE_FRAME_CAP)
+    }
+}
 
 /// Session handle result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SessionHandleResult {
     /// Indicates the session to start a migration
     Migrate,
@@ -26,34 +78,229 @@ pub enum SessionHandleResult {
     Pong,
 }
 
+/// Reason why a session ended, which is passed to `ShroomSessionHandler::finish`
+#[derive(Debug)]
+pub enum DisconnectReason<E> {
+    /// The client closed the connection
+    ClientClosed,
+    /// The client didn't respond to the previous ping
+    PingTimeout,
+    /// No packet was read within the read idle timeout
+    ReadIdleTimeout,
+    /// Writing didn't complete within the write stall timeout
+    WriteStallTimeout,
+    /// The client exceeded the rate limit for the opcode
+    RateLimited(Option<u16>),
+    /// An incoming frame could not be read or decoded
+    DecodeError(NetError),
+    /// The handler returned an error
+    HandlerError(E),
+    /// The server is shutting down
+    ServerShutdown,
+    /// The session was cancelled via `SharedSessionHandle` or `ShroomSessionHandle`
+    Cancelled,
+    /// The handler started a migration
+    Migration,
+    /// Frames of the `SharedSessionHandle` were dropped, see `MissedFramePolicy`
+    MissedFrame,
+}
+
+/// `DisconnectReason` without the errors, which is kept in the `SessionStats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectKind {
+    ClientClosed,
+    PingTimeout,
+    ReadIdleTimeout,
+    WriteStallTimeout,
+    RateLimited(Option<u16>),
+    DecodeError,
+    HandlerError,
+    ServerShutdown,
+    Cancelled,
+    Migration,
+    MissedFrame,
+}
+
+impl<E> DisconnectReason<E> {
+    /// Kind of the reason without the error
+    pub fn kind(&self) -> DisconnectKind {
+        match self {
+            Self::ClientClosed => DisconnectKind::ClientClosed,
+            Self::PingTimeout => DisconnectKind::PingTimeout,
+            Self::ReadIdleTimeout => DisconnectKind::ReadIdleTimeout,
+            Self::WriteStallTimeout => DisconnectKind::WriteStallTimeout,
+            Self::RateLimited(op) => DisconnectKind::RateLimited(*op),
+            Self::DecodeError(_) => DisconnectKind::DecodeError,
+            Self::HandlerError(_) => DisconnectKind::HandlerError,
+            Self::ServerShutdown => DisconnectKind::ServerShutdown,
+            Self::Cancelled => DisconnectKind::Cancelled,
+            Self::Migration => DisconnectKind::Migration,
+            Self::MissedFrame => DisconnectKind::MissedFrame,
+        }
+    }
+
+    /// Whether the session is migrating
+    pub fn is_migrating(&self) -> bool {
+        matches!(self, Self::Migration)
+    }
+
+    /// Whether the session ended because of an error
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::DecodeError(_) | Self::HandlerError(_))
+    }
+}
+
+impl<E> From<NetError> for DisconnectReason<E> {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::IO(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                ) =>
+            {
+                Self::ClientClosed
+            }
+            NetError::PingTimeout => Self::PingTimeout,
+            NetError::ReadIdleTimeout => Self::ReadIdleTimeout,
+            NetError::WriteStallTimeout => Self::WriteStallTimeout,
+            NetError::RateLimited(op) => Self::RateLimited(op),
+            NetError::MissedFrame => Self::MissedFrame,
+            err => Self::DecodeError(err),
+        }
+    }
+}
+
+/// Handle to send packets to a session or to cancel It, can be cloned.
+/// The session only drains the pipe between the handler calls, so the waiting sends
+/// don't wait when called from the task of the session and fail If the lane is full
 #[derive(Debug, Clone)]
 pub struct SharedSessionHandle {
     ct: CancellationToken,
-    tx: FramedPipeSender,
+    tx: PrioritySender,
+    session_task: Arc<OnceLock<tokio::task::Id>>,
+    str_codec: StringCodec,
 }
 
 impl SharedSessionHandle {
     /// Attempt to send a packet buffer to the session
     pub fn try_send_pkt_buf(&mut self, pkt_buf: &PacketBuffer) -> anyhow::Result<()> {
-        Ok(self.tx.clone().try_send_all(pkt_buf.packets())?)
+        self.try_send_pkt_buf_with(Priority::Normal, pkt_buf)
     }
 
     /// Attempt to send a single packet to the buffer
     pub fn try_send_pkt(&self, pkt: impl AsRef<[u8]>) -> anyhow::Result<()> {
-        Ok(self.tx.clone().try_send(pkt)?)
+        self.try_send_pkt_with(Priority::Normal, pkt)
+    }
+
+    /// Sends a single packet, waits until the pipe has enough capacity
+    pub async fn send_pkt(&self, pkt: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        self.send_pkt_with(Priority::Normal, pkt).await
+    }
+
+    /// Sends all packets of the buffer, waits until the pipe has enough capacity
+    pub async fn send_pkt_buf(&self, pkt_buf: &PacketBuffer) -> anyhow::Result<()> {
+        self.send_pkt_buf_with(Priority::Normal, pkt_buf).await
+    }
+
+    /// Attempt to send a packet buffer with the priority
+    pub fn try_send_pkt_buf_with(&self, prio: Priority, pkt_buf: &PacketBuffer) -> anyhow::Result<()> {
+        Ok(self.tx.clone().try_send_all(prio, pkt_buf.packets())?)
+    }
+
+    /// Attempt to send a single packet with the priority
+    pub fn try_send_pkt_with(&self, prio: Priority, pkt: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        Ok(self.tx.clone().try_send(prio, pkt)?)
+    }
+
+    /// Encodes the packet with the codec of the session and attempts to send It
+    pub fn try_send_encode_pkt<T: EncodePacket + HasOpcode>(&self, pkt: T) -> anyhow::Result<()> {
+        self.try_send_encode_pkt_with(Priority::Normal, pkt)
+    }
+
+    /// Encodes the packet with the codec of the session and attempts to send It with the priority
+    pub fn try_send_encode_pkt_with<T: EncodePacket + HasOpcode>(&self, prio: Priority, pkt: T) -> anyhow::Result<()> {
+        let mut pkt_buf = self.packet_buffer();
+        pkt_buf.encode_packet(pkt)?;
+        self.try_send_pkt_buf_with(prio, &pkt_buf)
+    }
+
+    /// Attempt to send shared bytes with the priority, the bytes are not copied
+    pub fn try_send_bytes_with(&self, prio: Priority, bytes: Bytes) -> anyhow::Result<()> {
+        Ok(self.tx.clone().try_send_bytes(prio, bytes)?)
+    }
+
+    /// Sends a single packet with the priority, waits until the lane has enough capacity
+    pub async fn send_pkt_with(&self, prio: Priority, pkt: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        if self.is_session_task() {
+            return self.try_send_pkt_with(prio, pkt);
+        }
+        Ok(self.tx.clone().send_frame(prio, pkt).await?)
+    }
+
+    /// Sends all packets of the buffer with the priority, waits until the lane has enough capacity
+    pub async fn send_pkt_buf_with(&self, prio: Priority, pkt_buf: &PacketBuffer) -> anyhow::Result<()> {
+        if self.is_session_task() {
+            return self.try_send_pkt_buf_with(prio, pkt_buf);
+        }
+        let mut tx = self.tx.clone();
+        for pkt in pkt_buf.packets() {
+            tx.send_frame(prio, pkt).await?;
+        }
+        Ok(())
     }
 }
 
 impl SharedSessionHandle {
-    pub fn new() -> (Self, FramedPipeReceiver) {
-        let (tx, rx) = framed_pipe::framed_pipe(8 * 1024, 128);
-        (
-            Self {
-                ct: CancellationToken::new(),
-                tx,
-            },
-            rx,
-        )
+    pub fn new() -> (Self, PriorityReceiver) {
+        Self::with_cancellation_token(CancellationToken::new())
+    }
+
+    /// Creates a handle, which closes the session once the token is cancelled
+    pub fn with_cancellation_token(ct: CancellationToken) -> (Self, PriorityReceiver) {
+        Self::with_pipe_config(ct, &PipeConfig::default())
+    }
+
+    /// Creates a handle with the pipe sizes of the config
+    pub fn with_pipe_config(ct: CancellationToken, cfg: &PipeConfig) -> (Self, PriorityReceiver) {
+        let (tx, rx) = priority_pipe(&[cfg.high, cfg.normal, cfg.low]);
+        (Self { ct, tx, session_task: Arc::default(), str_codec: StringCodec::default() }, rx)
+    }
+
+    /// Sets the codec of the session, which is used to encode the packets for this handle
+    pub fn with_str_codec(mut self, str_codec: StringCodec) -> Self {
+        self.str_codec = str_codec;
+        self
+    }
+
+    /// Codec of the session, packets for the session must encode their strings with It
+    pub fn str_codec(&self) -> StringCodec {
+        self.str_codec
+    }
+
+    /// Creates a packet buffer, which encodes the strings with the codec of the session
+    pub fn packet_buffer(&self) -> PacketBuffer {
+        PacketBuffer::with_str_codec(self.str_codec)
+    }
+
+    /// Signals the session to finish and close
+    pub fn cancel(&self) {
+        self.ct.cancel();
+    }
+
+    /// Remembers the current task as the task, which drains the pipe
+    pub(crate) fn set_session_task(&self) {
+        if let Some(id) = tokio::task::try_id() {
+            let _ = self.session_task.set(id);
+        }
+    }
+
+    /// Whether the current task drains the pipe, waiting for capacity would never complete then
+    fn is_session_task(&self) -> bool {
+        self.session_task.get().is_some_and(|id| tokio::task::try_id() == Some(*id))
     }
 }
 
@@ -61,15 +308,27 @@ pub struct ShroomContext<H: ShroomSessionHandler> {
     session: ShroomSession<H::Transport>,
     state: H,
     migrate: bool,
+    latency: LatencyStats,
+    phase_guard: Option<PhaseGuard>,
+    middleware: MiddlewareStack<H>,
+    requests: PendingRequests,
+    /// Packets, which were read while the handler awaited a response
+    deferred: VecDeque<ShroomPacket>,
     pub session_handle: SharedSessionHandle,
 }
 
 impl<H: ShroomSessionHandler> ShroomContext<H> {
     pub fn new(session: ShroomSession<H::Transport>, state: H, session_handle: SharedSessionHandle) -> Self {
+        let session_handle = session_handle.with_str_codec(session.str_codec());
         Self {
             session,
             state,
             migrate: false,
+            latency: LatencyStats::default(),
+            phase_guard: None,
+            middleware: MiddlewareStack::default(),
+            requests: PendingRequests::default(),
+            deferred: VecDeque::new(),
             session_handle
         }
     }
@@ -85,6 +344,11 @@ impl<H: ShroomSessionHandler + Send> ShroomContext<H> {
         Ok(resp.into_response().send(self).await?)
     }
 
+    /// Codec of the session, which is used for the strings of the packets
+    pub fn str_codec(&self) -> StringCodec {
+        self.session.str_codec()
+    }
+
     pub fn set_migrate(&mut self, migrate: bool) {
         self.migrate = migrate;
     }
@@ -92,6 +356,90 @@ impl<H: ShroomSessionHandler + Send> ShroomContext<H> {
     pub fn is_migrating(&self) -> bool {
         self.migrate
     }
+
+    /// Latency stats, which are measured with the ping packets of the server
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    /// Adds a middleware around the packet handler, the first added middleware is the outermost
+    pub fn layer(&mut self, middleware: impl PacketMiddleware<H> + 'static) {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+    }
+
+    /// Passes the packet through the middlewares to `ShroomSessionHandler::handle_packet`
+    pub async fn handle_packet(&mut self, packet: ShroomPacket) -> Result<SessionHandleResult, H::Error> {
+        let middleware = self.middleware.clone();
+        Next::new(&middleware).run(self, packet).await
+    }
+
+    /// Sets the guard, which checks the opcodes of the routed packets against the session phase.
+    /// The guard is only enforced by `shroom_router_fn!` and `RouteTable::dispatch`,
+    /// a handler which reads packets without them has to call `check_opcode` itself
+    pub fn set_phase_guard(&mut self, guard: PhaseGuard) {
+        self.phase_guard = Some(guard);
+    }
+
+    /// Current phase, If a phase guard is set
+    pub fn phase(&self) -> Option<SessionPhase> {
+        self.phase_guard.as_ref().map(PhaseGuard::phase)
+    }
+
+    /// Transitions the phase guard to the phase
+    pub fn set_phase(&mut self, phase: SessionPhase) {
+        if let Some(guard) = self.phase_guard.as_mut() {
+            guard.set_phase(phase);
+        }
+    }
+
+    /// Checks the opcode with the phase guard and returns whether the packet should be handled,
+    /// without a guard every opcode is allowed. This is called by `shroom_router_fn!`
+    pub fn check_opcode(&self, opcode: u16) -> Result<bool, NetError> {
+        self.phase_guard
+            .as_ref()
+            .map_or(Ok(true), |guard| guard.check(opcode))
+    }
+
+    /// Sends the request `req` and awaits the response with `Resp::OPCODE`, which fails
+    /// with `NetError::RequestTimeout` If no response arrives within `timeout`.
+    /// The session is read while waiting, responses of other pending requests are passed
+    /// to them and the other packets are handled after the current handler returned
+    pub async fn request<Req, Resp>(&mut self, req: Req, timeout: Duration) -> Result<Resp, H::Error>
+    where
+        Req: EncodePacket + HasOpcode + Send,
+        Resp: DecodePacketOwned + HasOpcode + Send,
+    {
+        let mut resp = self.send_request::<Req, Resp>(req, timeout).await?;
+        loop {
+            tokio::select! {
+                biased;
+                resp = &mut resp => return Ok(resp?),
+                p = self.session.read_packet() => {
+                    let Some(p) = self.requests.try_fill(p?) else {
+                        continue;
+                    };
+                    if self.deferred.len() >= MAX_DEFERRED_PACKETS {
+                        return Err(NetError::RequestBacklog(Resp::OPCODE.into()).into());
+                    }
+                    self.deferred.push_back(p);
+                }
+            }
+        }
+    }
+
+    /// Sends the request `req` and registers It as a pending request of the session,
+    /// the session passes the next packet with `Resp::OPCODE` to the returned future
+    /// instead of the handler. The session only reads between the handler calls,
+    /// so the future must be polled from `ShroomSessionHandler::poll_msg`,
+    /// `request` awaits the response within a handler
+    pub async fn send_request<Req, Resp>(&mut self, req: Req, timeout: Duration) -> Result<ResponseFuture<Resp>, H::Error>
+    where
+        Req: EncodePacket + HasOpcode + Send,
+        Resp: DecodePacketOwned + HasOpcode + Send,
+    {
+        self.send(req).await?;
+        Ok(self.requests.register(Resp::OPCODE.into(), timeout))
+    }
 }
 
 impl<H: ShroomSessionHandler> Deref for ShroomContext<H> {
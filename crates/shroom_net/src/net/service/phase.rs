@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{NetError, NetOpcode, NetResult};
+
+pub use crate::opcode::SessionPhase;
+
+/// Policy for packets, which are not allowed in the current phase
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnexpectedOpcodePolicy {
+    /// Drops the packet without handling It
+    Drop,
+    /// Closes the session with `NetError::UnexpectedOpcode`
+    #[default]
+    Disconnect,
+}
+
+/// Guards the opcodes, which are allowed per session phase,
+/// a phase without any allowed opcodes only allows the opcodes of `allow_always`
+#[derive(Debug, Default, Clone)]
+pub struct PhaseGuard {
+    phase: SessionPhase,
+    allowed: HashMap<SessionPhase, HashSet<u16>>,
+    always: HashSet<u16>,
+    policy: UnexpectedOpcodePolicy,
+}
+
+impl PhaseGuard {
+    /// Creates a guard, which starts in the `initial` phase
+    pub fn new(initial: SessionPhase) -> Self {
+        Self {
+            phase: initial,
+            ..Default::default()
+        }
+    }
+
+    /// Allows the opcodes in the phase
+    pub fn allow<Op: NetOpcode>(
+        mut self,
+        phase: SessionPhase,
+        ops: impl IntoIterator<Item = Op>,
+    ) -> Self {
+        self.allowed
+            .entry(phase)
+            .or_default()
+            .extend(ops.into_iter().map(Into::into));
+        self
+    }
+
+    /// Allows the opcodes in every phase, for example the pong
+    pub fn allow_always<Op: NetOpcode>(mut self, ops: impl IntoIterator<Item = Op>) -> Self {
+        self.always.extend(ops.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_policy(mut self, policy: UnexpectedOpcodePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Current phase
+    pub fn phase(&self) -> SessionPhase {
+        self.phase
+    }
+
+    /// Transitions to the phase
+    pub fn set_phase(&mut self, phase: SessionPhase) {
+        self.phase = phase;
+    }
+
+    /// Whether the opcode is allowed in the current phase
+    pub fn is_allowed(&self, opcode: u16) -> bool {
+        self.always.contains(&opcode)
+            || self
+                .allowed
+                .get(&self.phase)
+                .is_some_and(|ops| ops.contains(&opcode))
+    }
+
+    /// Checks the opcode against the current phase and returns whether the packet should be handled,
+    /// If It's not allowed the packet is either dropped or an error is returned depending on the policy
+    pub fn check(&self, opcode: u16) -> NetResult<bool> {
+        if self.is_allowed(opcode) {
+            return Ok(true);
+        }
+
+        match self.policy {
+            UnexpectedOpcodePolicy::Drop => {
+                log::trace!("Dropped opcode {opcode:X} in phase {:?}", self.phase);
+                Ok(false)
+            }
+            UnexpectedOpcodePolicy::Disconnect => Err(NetError::UnexpectedOpcode {
+                phase: self.phase,
+                opcode,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NetError;
+
+    use super::{PhaseGuard, SessionPhase, UnexpectedOpcodePolicy};
+
+    #[test]
+    fn phase_guard() {
+        let mut guard = PhaseGuard::new(SessionPhase::LoggedOut)
+            .allow(SessionPhase::LoggedOut, [1u16])
+            .allow(SessionPhase::LoggedIn, [2u16, 3])
+            .allow_always([0x11u16]);
+
+        assert!(guard.check(1).unwrap());
+        assert!(guard.check(0x11).unwrap());
+        assert!(matches!(
+            guard.check(2),
+            Err(NetError::UnexpectedOpcode {
+                phase: SessionPhase::LoggedOut,
+                opcode: 2
+            })
+        ));
+
+        guard.set_phase(SessionPhase::LoggedIn);
+        assert!(guard.check(2).unwrap());
+        assert!(guard.check(1).is_err());
+
+        // Phase without any allowed opcodes
+        guard.set_phase(SessionPhase::InGame);
+        assert!(guard.check(0x11).unwrap());
+        assert!(guard.check(2).is_err());
+
+        let guard = guard.with_policy(UnexpectedOpcodePolicy::Drop);
+        assert!(!guard.check(2).unwrap());
+    }
+}
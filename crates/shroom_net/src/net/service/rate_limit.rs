@@ -0,0 +1,241 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{NetError, NetOpcode, NetResult};
+
+/// Config for a token bucket, which allows `burst` packets at once
+/// and refills `rate` tokens per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucketConfig {
+    pub burst: u32,
+    pub rate: f64,
+}
+
+impl TokenBucketConfig {
+    pub const fn new(burst: u32, rate: f64) -> Self {
+        Self { burst, rate }
+    }
+}
+
+/// Policy for packets which exceed the limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Drops the packet without handling It
+    #[default]
+    Drop,
+    /// Waits until a token is available, which also stops reading from the client.
+    /// Pings and the session pipe are still sent while waiting
+    Delay,
+    /// Closes the session with `NetError::RateLimited`
+    Disconnect,
+}
+
+/// Inbound rate limits of a session, without buckets nothing is limited
+#[derive(Debug, Default, Clone)]
+pub struct RateLimitConfig {
+    /// Bucket for all packets
+    pub global: Option<TokenBucketConfig>,
+    /// Buckets per opcode, which are checked additionally to the global bucket
+    pub per_opcode: HashMap<u16, TokenBucketConfig>,
+    pub policy: RateLimitPolicy,
+}
+
+impl RateLimitConfig {
+    pub fn with_global(mut self, bucket: TokenBucketConfig) -> Self {
+        self.global = Some(bucket);
+        self
+    }
+
+    pub fn with_opcode(mut self, op: impl NetOpcode, bucket: TokenBucketConfig) -> Self {
+        self.per_opcode.insert(op.into(), bucket);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Checks the config, every bucket must hold at least one token and refill,
+    /// otherwise the limited packets would be dropped or delayed forever
+    pub fn validate(&self) -> NetResult<()> {
+        let invalid = self
+            .global
+            .iter()
+            .chain(self.per_opcode.values())
+            .any(|b| b.burst == 0 || !(b.rate > 0.0 && b.rate.is_finite()));
+        if invalid {
+            return Err(NetError::InvalidRateLimit(
+                "a bucket requires a positive finite rate and a burst of at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    cfg: TokenBucketConfig,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(cfg: TokenBucketConfig, now: Instant) -> Self {
+        Self {
+            cfg,
+            tokens: cfg.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.cfg.rate).min(self.cfg.burst as f64);
+        self.last = now;
+    }
+
+    /// Time until a token is available
+    fn wait_time(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+
+        // Saturate for invalid and tiny rates, which would overflow the duration
+        Duration::try_from_secs_f64((1.0 - self.tokens) / self.cfg.rate).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Token bucket limiter for the inbound packets of a session
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    per_opcode: HashMap<u16, TokenBucket>,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            global: cfg.global.map(|b| TokenBucket::new(b, now)),
+            per_opcode: cfg
+                .per_opcode
+                .iter()
+                .map(|(&op, &b)| (op, TokenBucket::new(b, now)))
+                .collect(),
+            policy: cfg.policy,
+        }
+    }
+
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.policy
+    }
+
+    /// Whether any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.global.is_some() || !self.per_opcode.is_empty()
+    }
+
+    /// Takes a token for the packet with the optional opcode,
+    /// If there's no token available no token is taken and the time until
+    /// a token is available is returned
+    pub fn try_acquire(&mut self, opcode: Option<u16>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut global = self.global.as_mut();
+        let mut op = opcode.and_then(|op| self.per_opcode.get_mut(&op));
+
+        // Check all buckets first, so no token is taken If one of them is empty
+        let mut wait = Duration::ZERO;
+        for bucket in [global.as_deref_mut(), op.as_deref_mut()]
+            .into_iter()
+            .flatten()
+        {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time());
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for bucket in [global, op].into_iter().flatten() {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RateLimitConfig, RateLimitPolicy, RateLimiter, TokenBucketConfig};
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket() {
+        let mut limiter = RateLimiter::new(
+            &RateLimitConfig::default()
+                .with_global(TokenBucketConfig::new(3, 1.0))
+                .with_opcode(1u16, TokenBucketConfig::new(1, 0.5)),
+        );
+
+        assert!(limiter.try_acquire(Some(1)).is_ok());
+        // Opcode bucket is empty, the global token must not be taken
+        assert_eq!(limiter.try_acquire(Some(1)), Err(Duration::from_secs(2)));
+        assert!(limiter.try_acquire(Some(2)).is_ok());
+        assert!(limiter.try_acquire(None).is_ok());
+        assert_eq!(limiter.try_acquire(None), Err(Duration::from_secs(1)));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(limiter.try_acquire(Some(1)).is_ok());
+        assert!(limiter.try_acquire(Some(2)).is_ok());
+        assert!(limiter.try_acquire(Some(2)).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        for policy in [
+            RateLimitPolicy::Drop,
+            RateLimitPolicy::Delay,
+            RateLimitPolicy::Disconnect,
+        ] {
+            let cfg = RateLimitConfig::default().with_policy(policy);
+            for bucket in [
+                TokenBucketConfig::new(1, 0.0),
+                TokenBucketConfig::new(1, -1.0),
+                TokenBucketConfig::new(1, f64::NAN),
+                TokenBucketConfig::new(1, f64::INFINITY),
+                TokenBucketConfig::new(0, 1.0),
+            ] {
+                assert!(cfg.clone().with_global(bucket).validate().is_err());
+                assert!(cfg.clone().with_opcode(1u16, bucket).validate().is_err());
+            }
+            assert!(cfg
+                .with_global(TokenBucketConfig::new(1, 1.0))
+                .validate()
+                .is_ok());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_time_saturates() {
+        for rate in [1e-20, 0.0] {
+            let mut limiter = RateLimiter::new(
+                &RateLimitConfig::default().with_global(TokenBucketConfig::new(1, rate)),
+            );
+            assert!(limiter.try_acquire(None).is_ok());
+            assert_eq!(limiter.try_acquire(None), Err(Duration::MAX));
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig::default());
+        assert!(!limiter.is_enabled());
+        for _ in 0..1000 {
+            assert!(limiter.try_acquire(Some(1)).is_ok());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::channel::oneshot;
+use tokio::time::{Instant, Sleep};
+
+use crate::{packet::DecodePacketOwned, NetError, NetResult, ShroomPacket};
+
+/// Max number of packets, which are kept for the handler while It awaits a response
+pub(crate) const MAX_DEFERRED_PACKETS: usize = 256;
+
+#[derive(Debug)]
+struct PendingRequest {
+    deadline: Instant,
+    tx: oneshot::Sender<ShroomPacket>,
+}
+
+impl PendingRequest {
+    /// Whether the request timed out or Its future was dropped
+    fn is_done(&self, now: Instant) -> bool {
+        self.tx.is_canceled() || now >= self.deadline
+    }
+}
+
+/// Pending requests of a session keyed by the response opcode, which are filled by the session.
+/// Requests for the same opcode are filled in the order they were registered
+#[derive(Debug, Default)]
+pub(crate) struct PendingRequests {
+    pending: HashMap<u16, VecDeque<PendingRequest>>,
+}
+
+impl PendingRequests {
+    /// Registers a request for the response opcode
+    pub(crate) fn register<Resp>(
+        &mut self,
+        opcode: u16,
+        timeout: Duration,
+    ) -> ResponseFuture<Resp> {
+        let now = Instant::now();
+        // Drop the requests, which will never be filled
+        self.pending.retain(|_, reqs| {
+            reqs.retain(|req| !req.is_done(now));
+            !reqs.is_empty()
+        });
+
+        let deadline = now + timeout;
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .entry(opcode)
+            .or_default()
+            .push_back(PendingRequest { deadline, tx });
+
+        ResponseFuture {
+            rx,
+            timeout: Box::pin(tokio::time::sleep_until(deadline)),
+            opcode,
+            _resp: PhantomData,
+        }
+    }
+
+    /// Passes the packet to the oldest pending request for Its opcode, If there's one.
+    /// Otherwise the packet is returned to be handled by the handler
+    pub(crate) fn try_fill(&mut self, pkt: ShroomPacket) -> Option<ShroomPacket> {
+        let Ok(opcode) = pkt.read_opcode() else {
+            return Some(pkt);
+        };
+        let Some(reqs) = self.pending.get_mut(&opcode) else {
+            return Some(pkt);
+        };
+
+        // Skip the requests, which timed out or whose future was dropped
+        let now = Instant::now();
+        let req = std::iter::from_fn(|| reqs.pop_front()).find(|req| !req.is_done(now));
+        if reqs.is_empty() {
+            self.pending.remove(&opcode);
+        }
+
+        match req {
+            // The receiver might be dropped meanwhile, the response is discarded then
+            Some(req) => {
+                let _ = req.tx.send(pkt);
+                None
+            }
+            None => Some(pkt),
+        }
+    }
+}
+
+/// Future for the response of `ShroomContext::request`
+#[derive(Debug)]
+pub struct ResponseFuture<Resp> {
+    rx: oneshot::Receiver<ShroomPacket>,
+    timeout: Pin<Box<Sleep>>,
+    opcode: u16,
+    _resp: PhantomData<fn() -> Resp>,
+}
+
+impl<Resp: DecodePacketOwned> ResponseFuture<Resp> {
+    fn decode(pkt: ShroomPacket) -> NetResult<Resp> {
+        let mut pr = pkt.into_reader();
+        pr.read_opcode::<u16>()?;
+        Resp::decode_packet(&mut pr)
+    }
+}
+
+impl<Resp: DecodePacketOwned> Future for ResponseFuture<Resp> {
+    type Output = NetResult<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Ready(Ok(pkt)) => return Poll::Ready(Self::decode(pkt)),
+            Poll::Ready(Err(_)) => {
+                return Poll::Ready(Err(NetError::RequestCancelled(self.opcode)))
+            }
+            Poll::Pending => {}
+        }
+
+        match self.timeout.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(NetError::RequestTimeout(self.opcode))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{NetError, ShroomPacket};
+
+    use super::PendingRequests;
+
+    fn resp(opcode: u8, v: u8) -> ShroomPacket {
+        ShroomPacket::from_data(vec![opcode, 0x0, v, 0x0].into())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_requests() {
+        let mut reqs = PendingRequests::default();
+        let first = reqs.register::<u16>(0x12, Duration::from_secs(1));
+        let second = reqs.register::<u16>(0x12, Duration::from_secs(1));
+        let other = reqs.register::<u16>(0x13, Duration::from_secs(1));
+
+        // Unrelated packets are returned
+        assert!(reqs
+            .try_fill(ShroomPacket::from_data(vec![0x1, 0x0].into()))
+            .is_some());
+        // Requests are filled by opcode and in order
+        assert!(reqs.try_fill(resp(0x13, 3)).is_none());
+        assert!(reqs.try_fill(resp(0x12, 1)).is_none());
+        assert!(reqs.try_fill(resp(0x12, 2)).is_none());
+        assert_eq!(first.await.unwrap(), 1);
+        assert_eq!(second.await.unwrap(), 2);
+        assert_eq!(other.await.unwrap(), 3);
+
+        // A dropped request is skipped
+        drop(reqs.register::<u16>(0x12, Duration::from_secs(1)));
+        let pending = reqs.register::<u16>(0x12, Duration::from_secs(1));
+        assert!(reqs.try_fill(resp(0x12, 4)).is_none());
+        assert_eq!(pending.await.unwrap(), 4);
+
+        // The response after the timeout is passed to the handler
+        let timeout = reqs.register::<u16>(0x12, Duration::from_secs(1));
+        assert!(matches!(timeout.await, Err(NetError::RequestTimeout(0x12))));
+        assert!(reqs.try_fill(resp(0x12, 5)).is_some());
+    }
+}
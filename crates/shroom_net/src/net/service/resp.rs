@@ -115,16 +115,17 @@ where
     }
 }
 
-/// Response which does nothing but signals that a Pong was handled via `SessionHandleResult`
+/// Response which sends nothing but records the pong for the latency stats,
+/// returning `SessionHandleResult::Pong` has the same effect
 pub struct PongResponse;
 
 #[async_trait]
 impl Response for PongResponse {
     async fn send<H: ShroomSessionHandler + Send>(
         self,
-        _ctx: &mut ShroomContext<H>,
+        ctx: &mut ShroomContext<H>,
     ) -> NetResult<()> {
-        // TODO
+        ctx.latency.pong_received(tokio::time::Instant::now());
         Ok(())
     }
 }
@@ -218,6 +219,7 @@ where
 mod tests {
     use super::{IntoResponse, ResponsePacket};
 
+    #[allow(clippy::extra_unused_type_parameters)]
     fn check_is_into_response<T>() -> bool
     where
         T: IntoResponse,
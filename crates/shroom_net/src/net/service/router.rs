@@ -0,0 +1,548 @@
+use std::{any::type_name, collections::HashMap, fmt::Debug, pin::Pin, sync::Arc};
+
+use futures::Future;
+use thiserror::Error;
+
+use crate::{packet::DecodePacketOwned, DecodePacket, HasOpcode, NetError, PacketReader};
+
+use super::{handler::ShroomSessionHandler, ShroomContext};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Opcodes below this are routed with a table indexed by the opcode,
+/// higher opcodes are looked up in a map, to keep the table small
+const DENSE_TABLE_LEN: usize = 0x1000;
+
+type RouteFn<H> = Arc<
+    dyn for<'a> Fn(
+            &'a mut ShroomContext<H>,
+            PacketReader<'a>,
+        ) -> BoxFuture<'a, Result<(), <H as ShroomSessionHandler>::Error>>
+        + Send
+        + Sync,
+>;
+
+type FallbackFn<H> = Arc<
+    dyn for<'a> Fn(
+            &'a mut ShroomContext<H>,
+            u16,
+            PacketReader<'a>,
+        ) -> BoxFuture<'a, Result<(), <H as ShroomSessionHandler>::Error>>
+        + Send
+        + Sync,
+>;
+
+/// Request type, which borrows from the packet like `&str`.
+/// `Req<'a>` is the request, which is decoded from a packet with the lifetime `'a`
+pub trait BorrowedRequest: 'static {
+    type Req<'a>: DecodePacket<'a> + HasOpcode + Send;
+}
+
+/// Handler of a route, implemented for async fns like
+/// `async fn handle(ctx: &mut ShroomContext<H>, req: Req) -> Result<(), H::Error>`
+pub trait RouteHandler<'a, H: ShroomSessionHandler, Req>: Send + Sync {
+    type Fut: Future<Output = Result<(), H::Error>> + Send + 'a;
+
+    fn call(&self, ctx: &'a mut ShroomContext<H>, req: Req) -> Self::Fut;
+}
+
+impl<'a, H, Req, F, Fut> RouteHandler<'a, H, Req> for F
+where
+    H: ShroomSessionHandler + 'a,
+    F: Fn(&'a mut ShroomContext<H>, Req) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), H::Error>> + Send + 'a,
+{
+    type Fut = Fut;
+
+    fn call(&self, ctx: &'a mut ShroomContext<H>, req: Req) -> Self::Fut {
+        self(ctx, req)
+    }
+}
+
+/// Fallback handler for unrouted opcodes, implemented for async fns like
+/// `async fn fallback(ctx: &mut ShroomContext<H>, op: u16, pr: PacketReader<'_>) -> Result<(), H::Error>`
+pub trait FallbackHandler<'a, H: ShroomSessionHandler>: Send + Sync {
+    type Fut: Future<Output = Result<(), H::Error>> + Send + 'a;
+
+    fn call(&self, ctx: &'a mut ShroomContext<H>, op: u16, pr: PacketReader<'a>) -> Self::Fut;
+}
+
+impl<'a, H, F, Fut> FallbackHandler<'a, H> for F
+where
+    H: ShroomSessionHandler + 'a,
+    F: Fn(&'a mut ShroomContext<H>, u16, PacketReader<'a>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), H::Error>> + Send + 'a,
+{
+    type Fut = Fut;
+
+    fn call(&self, ctx: &'a mut ShroomContext<H>, op: u16, pr: PacketReader<'a>) -> Self::Fut {
+        self(ctx, op, pr)
+    }
+}
+
+/// Error while building a router
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RouterError {
+    #[error("Duplicate route for opcode {opcode:X}: {first} and {second}")]
+    DuplicateOpcode {
+        opcode: u16,
+        first: &'static str,
+        second: &'static str,
+    },
+    #[error("Duplicate fallback handler")]
+    DuplicateFallback,
+}
+
+struct Route<H: ShroomSessionHandler> {
+    opcode: u16,
+    req: &'static str,
+    handler: RouteFn<H>,
+}
+
+/// Builder for a `RouteTable`, which routes the packets by the `OPCODE` of the request type.
+/// Routers can be built across modules and combined with `merge`
+pub struct Router<H: ShroomSessionHandler> {
+    routes: Vec<Route<H>>,
+    fallbacks: Vec<FallbackFn<H>>,
+}
+
+impl<H: ShroomSessionHandler> Default for Router<H> {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallbacks: Vec::new(),
+        }
+    }
+}
+
+impl<H: ShroomSessionHandler> Debug for Router<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field(
+                "routes",
+                &self
+                    .routes
+                    .iter()
+                    .map(|r| (r.opcode, r.req))
+                    .collect::<Vec<_>>(),
+            )
+            .field("fallbacks", &self.fallbacks.len())
+            .finish()
+    }
+}
+
+impl<H> Router<H>
+where
+    H: ShroomSessionHandler + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes the packets with `Req::OPCODE` to the handler
+    pub fn route<Req, F>(mut self, handler: F) -> Self
+    where
+        Req: DecodePacketOwned + HasOpcode + Send + 'static,
+        F: for<'a> RouteHandler<'a, H, Req> + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.routes.push(Route {
+            opcode: Req::OPCODE.into(),
+            req: type_name::<Req>(),
+            handler: erase_route(move |ctx, mut pr| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let req = Req::decode_packet(&mut pr)?;
+                    handler.call(ctx, req).await
+                })
+            }),
+        });
+        self
+    }
+
+    /// Routes the packets with the opcode of the borrowed request `R::Req` to the handler,
+    /// the request borrows from the packet instead of being copied
+    pub fn route_borrowed<R, F>(mut self, handler: F) -> Self
+    where
+        R: BorrowedRequest,
+        F: for<'a> RouteHandler<'a, H, R::Req<'a>> + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.routes.push(Route {
+            opcode: <R::Req<'static> as HasOpcode>::OPCODE.into(),
+            req: type_name::<R>(),
+            handler: erase_route(move |ctx, mut pr| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let req = R::Req::decode_packet(&mut pr)?;
+                    handler.call(ctx, req).await
+                })
+            }),
+        });
+        self
+    }
+
+    /// Handler for all opcodes without a route
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: for<'a> FallbackHandler<'a, H> + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.fallbacks.push(erase_fallback(move |ctx, op, pr| {
+            let handler = handler.clone();
+            Box::pin(async move { handler.call(ctx, op, pr).await })
+        }));
+        self
+    }
+
+    /// Adds the routes and the fallback of the other router
+    pub fn merge(mut self, other: Router<H>) -> Self {
+        self.routes.extend(other.routes);
+        self.fallbacks.extend(other.fallbacks);
+        self
+    }
+
+    /// Builds the dispatch table, fails If an opcode has multiple routes
+    /// or there's more than one fallback.
+    /// The table has a slot for every opcode up to the highest routed opcode,
+    /// opcodes from `0x1000` are kept in a map instead
+    pub fn build(self) -> Result<RouteTable<H>, RouterError> {
+        if self.fallbacks.len() > 1 {
+            return Err(RouterError::DuplicateFallback);
+        }
+
+        let mut routes: HashMap<u16, Route<H>> = HashMap::with_capacity(self.routes.len());
+        for route in self.routes {
+            if let Some(first) = routes.get(&route.opcode) {
+                return Err(RouterError::DuplicateOpcode {
+                    opcode: route.opcode,
+                    first: first.req,
+                    second: route.req,
+                });
+            }
+            routes.insert(route.opcode, route);
+        }
+
+        let len = routes
+            .keys()
+            .map(|&op| op as usize + 1)
+            .filter(|&len| len <= DENSE_TABLE_LEN)
+            .max()
+            .unwrap_or(0);
+        let mut table: Vec<Option<RouteFn<H>>> = vec![None; len];
+        let mut sparse = HashMap::new();
+        for (op, route) in routes {
+            match table.get_mut(op as usize) {
+                Some(slot) => *slot = Some(route.handler),
+                None => {
+                    sparse.insert(op, route.handler);
+                }
+            }
+        }
+
+        Ok(RouteTable {
+            table,
+            sparse,
+            fallback: self.fallbacks.into_iter().next(),
+        })
+    }
+}
+
+fn erase_route<H, F>(f: F) -> RouteFn<H>
+where
+    H: ShroomSessionHandler,
+    F: for<'a> Fn(
+            &'a mut ShroomContext<H>,
+            PacketReader<'a>,
+        ) -> BoxFuture<'a, Result<(), H::Error>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Arc::new(f)
+}
+
+fn erase_fallback<H, F>(f: F) -> FallbackFn<H>
+where
+    H: ShroomSessionHandler,
+    F: for<'a> Fn(
+            &'a mut ShroomContext<H>,
+            u16,
+            PacketReader<'a>,
+        ) -> BoxFuture<'a, Result<(), H::Error>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Arc::new(f)
+}
+
+/// Built router, which dispatches the packets with a table indexed by the opcode
+pub struct RouteTable<H: ShroomSessionHandler> {
+    table: Vec<Option<RouteFn<H>>>,
+    /// Routes of the opcodes, which are too high for the table
+    sparse: HashMap<u16, RouteFn<H>>,
+    fallback: Option<FallbackFn<H>>,
+}
+
+impl<H: ShroomSessionHandler> Clone for RouteTable<H> {
+    fn clone(&self) -> Self {
+        Self {
+            table: self.table.clone(),
+            sparse: self.sparse.clone(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+impl<H: ShroomSessionHandler> Debug for RouteTable<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouteTable")
+            .field(
+                "routes",
+                &(self.table.iter().flatten().count() + self.sparse.len()),
+            )
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl<H> RouteTable<H>
+where
+    H: ShroomSessionHandler + Send,
+{
+    fn get(&self, opcode: u16) -> Option<&RouteFn<H>> {
+        match self.table.get(opcode as usize) {
+            Some(route) => route.as_ref(),
+            None => self.sparse.get(&opcode),
+        }
+    }
+
+    /// Whether the opcode has a route
+    pub fn has_route(&self, opcode: u16) -> bool {
+        self.get(opcode).is_some()
+    }
+
+    /// Reads the opcode and passes the packet to the route or the fallback,
+    /// packets which are not allowed by the phase guard of the context are not routed.
+    /// Without a fallback unrouted opcodes fail with `NetError::InvalidOpcode`
+    pub async fn dispatch<'a>(
+        &self,
+        ctx: &'a mut ShroomContext<H>,
+        mut pr: PacketReader<'a>,
+    ) -> Result<(), H::Error> {
+        let op: u16 = pr.read_opcode()?;
+        if !ctx.check_opcode(op)? {
+            return Ok(());
+        }
+
+        match (self.get(op), &self.fallback) {
+            (Some(route), _) => route(ctx, pr).await,
+            (None, Some(fallback)) => fallback(ctx, op, pr).await,
+            (None, None) => Err(NetError::InvalidOpcode(op).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        crypto::SharedCryptoContext,
+        net::{
+            service::{
+                BasicHandshakeGenerator, HandshakeGenerator, SessionHandleResult,
+                SharedSessionHandle, ShroomContext,
+            },
+            ShroomSession,
+        },
+        opcode::WithOpcode,
+        NetError, PacketReader, PacketWriter, ShroomPacket,
+    };
+
+    use super::{BorrowedRequest, Router, RouterError, ShroomSessionHandler};
+
+    type Req1 = WithOpcode<0, u16>;
+    type Req2 = WithOpcode<1, ()>;
+    type Ctx = ShroomContext<Handler>;
+
+    struct NameReq;
+    impl BorrowedRequest for NameReq {
+        type Req<'a> = WithOpcode<3, &'a str>;
+    }
+
+    #[derive(Debug, Default)]
+    struct Handler {
+        req1: u16,
+        name: String,
+        fallback: Option<u16>,
+    }
+
+    impl Handler {
+        async fn handle_req1(ctx: &mut Ctx, req: Req1) -> anyhow::Result<()> {
+            ctx.req1 = req.0;
+            Ok(())
+        }
+
+        async fn handle_req2(ctx: &mut Ctx, _req: Req2) -> anyhow::Result<()> {
+            ctx.req1 *= 2;
+            Ok(())
+        }
+
+        async fn handle_unit(_ctx: &mut Ctx, _req: WithOpcode<0, ()>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn handle_high(ctx: &mut Ctx, req: WithOpcode<0xF000, u16>) -> anyhow::Result<()> {
+            ctx.req1 = req.0;
+            Ok(())
+        }
+
+        async fn handle_name(ctx: &mut Ctx, req: WithOpcode<3, &str>) -> anyhow::Result<()> {
+            ctx.name = req.0.to_string();
+            Ok(())
+        }
+
+        async fn handle_fallback(
+            ctx: &mut Ctx,
+            op: u16,
+            _pr: PacketReader<'_>,
+        ) -> anyhow::Result<()> {
+            ctx.fallback = Some(op);
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ShroomSessionHandler for Handler {
+        type Transport = std::io::Cursor<Vec<u8>>;
+        type Error = anyhow::Error;
+        type Msg = ();
+
+        async fn handle_packet(
+            _ctx: &mut ShroomContext<Self>,
+            _packet: ShroomPacket,
+        ) -> Result<SessionHandleResult, Self::Error> {
+            Ok(SessionHandleResult::Ok)
+        }
+
+        async fn handle_msg(
+            _ctx: &mut ShroomContext<Self>,
+            _msg: Self::Msg,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn pkt(op: u16, data: Option<u16>) -> ShroomPacket {
+        let mut pw = PacketWriter::default();
+        pw.write_opcode(op).expect("Encode");
+        if let Some(data) = data {
+            pw.write_u16(data).expect("Encode");
+        }
+        pw.into_packet()
+    }
+
+    #[tokio::test]
+    async fn router() {
+        let hshake = BasicHandshakeGenerator::v83().generate_handshake();
+        let sess = ShroomSession::from_client_handshake(
+            std::io::Cursor::new(vec![]),
+            SharedCryptoContext::default(),
+            hshake,
+        );
+        let mut ctx = ShroomContext::new(sess, Handler::default(), SharedSessionHandle::new().0);
+
+        // Routes from another module
+        let other = Router::new().route::<Req2, _>(Handler::handle_req2);
+        let router = Router::new()
+            .route::<Req1, _>(Handler::handle_req1)
+            .merge(other)
+            .build()
+            .unwrap();
+        assert!(router.has_route(1));
+
+        router
+            .dispatch(&mut ctx, pkt(0, Some(21)).into_reader())
+            .await
+            .unwrap();
+        router
+            .dispatch(&mut ctx, pkt(1, None).into_reader())
+            .await
+            .unwrap();
+        assert_eq!(ctx.req1, 42);
+
+        let err = router
+            .dispatch(&mut ctx, pkt(5, None).into_reader())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast().unwrap(),
+            NetError::InvalidOpcode(5)
+        ));
+
+        let router = Router::new()
+            .fallback(Handler::handle_fallback)
+            .build()
+            .unwrap();
+        router
+            .dispatch(&mut ctx, pkt(5, None).into_reader())
+            .await
+            .unwrap();
+        assert_eq!(ctx.fallback, Some(5));
+    }
+
+    #[tokio::test]
+    async fn borrowed_and_sparse_routes() {
+        let hshake = BasicHandshakeGenerator::v83().generate_handshake();
+        let sess = ShroomSession::from_client_handshake(
+            std::io::Cursor::new(vec![]),
+            SharedCryptoContext::default(),
+            hshake,
+        );
+        let mut ctx = ShroomContext::new(sess, Handler::default(), SharedSessionHandle::new().0);
+
+        let router = Router::new()
+            .route_borrowed::<NameReq, _>(Handler::handle_name)
+            .route::<WithOpcode<0xF000, u16>, _>(Handler::handle_high)
+            .build()
+            .unwrap();
+        assert!(router.has_route(3));
+        assert!(router.has_route(0xF000));
+        assert!(!router.has_route(0xF001));
+
+        let mut pw = PacketWriter::default();
+        pw.write_opcode(3u16).expect("Encode");
+        pw.write_str("shroom").expect("Encode");
+        router
+            .dispatch(&mut ctx, pw.into_packet().into_reader())
+            .await
+            .unwrap();
+        assert_eq!(ctx.name, "shroom");
+
+        router
+            .dispatch(&mut ctx, pkt(0xF000, Some(7)).into_reader())
+            .await
+            .unwrap();
+        assert_eq!(ctx.req1, 7);
+    }
+
+    #[test]
+    fn duplicates() {
+        let err = Router::<Handler>::new()
+            .route::<Req1, _>(Handler::handle_req1)
+            .merge(Router::new().route::<WithOpcode<0, ()>, _>(Handler::handle_unit))
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::DuplicateOpcode { opcode: 0, .. }
+        ));
+
+        let err = Router::<Handler>::new()
+            .fallback(Handler::handle_fallback)
+            .merge(Router::new().fallback(Handler::handle_fallback))
+            .build()
+            .unwrap_err();
+        assert_eq!(err, RouterError::DuplicateFallback);
+    }
+}
@@ -1,24 +1,30 @@
 use std::{fmt::Debug, io, marker::PhantomData, sync::Arc, time::Duration};
 
-use futures::{Stream, StreamExt};
+use bytes::Bytes;
+use futures::{Future, Stream, StreamExt};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     crypto::SharedCryptoContext,
-    net::{codec::handshake::Handshake, service::SessionHandleResult, ShroomSession},
-    util::framed_pipe::FramedPipeReceiver,
-    NetError, ShroomPacket,
+    net::{codec::handshake::Handshake, service::SessionHandleResult, PeerAddr, ShroomSession},
+    util::priority_pipe::PriorityReceiver,
+    NetError, NetResult, ShroomPacket,
 };
 
 use super::{
-    handler::{MakeServerSessionHandler, ShroomSessionHandler},
-    HandshakeGenerator, SharedSessionHandle, ShroomContext,
+    admission::{AcceptFilter, Admission, ConnectionGuard, ConnectionLimits, RejectReason},
+    handler::{MakeServerSessionHandler, SessionOutcome, SessionStats, ShroomSessionHandler},
+    rate_limit::{RateLimitConfig, RateLimitPolicy, RateLimiter},
+    DisconnectReason, HandshakeGenerator, MissedFramePolicy, PipeConfig, SharedSessionHandle,
+    ShroomContext,
 };
 
 #[derive(Debug)]
 pub struct ShroomSessionHandle<H: ShroomSessionHandler> {
     pub handle: tokio::task::JoinHandle<Result<(), H::Error>>,
+    ct: CancellationToken,
     _handler: PhantomData<H>,
 }
 
@@ -30,12 +36,64 @@ where
     pub fn is_active(&self) -> bool {
         !self.handle.is_finished()
     }
+
+    /// Signals the session to finish and close
+    pub fn cancel(&self) {
+        self.ct.cancel();
+    }
+}
+
+/// Summary of the sessions, which were still active during the shutdown
+#[derive(Debug)]
+pub struct ShutdownSummary<E> {
+    /// Sessions which finished without an error
+    pub finished: usize,
+    /// Errors of the sessions which failed
+    pub errors: Vec<E>,
+    /// Sessions which panicked
+    pub panicked: usize,
+    /// Sessions which were aborted, because they didn't finish before the deadline
+    pub aborted: usize,
+}
+
+impl<E> Default for ShutdownSummary<E> {
+    fn default() -> Self {
+        Self {
+            finished: 0,
+            errors: Vec::new(),
+            panicked: 0,
+            aborted: 0,
+        }
+    }
+}
+
+impl<E> ShutdownSummary<E> {
+    /// Whether every session finished without an error
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty() && self.panicked == 0 && self.aborted == 0
+    }
+}
+
+/// Max frames, which are drained from the session pipe and sent with a single flush
+const MAX_SEND_BATCH: usize = 128;
+
+/// Action for an incoming packet, after applying the rate limit
+enum RateLimitAction {
+    Handle,
+    Drop,
+    /// Handles the packet again at the deadline
+    Delay(tokio::time::Instant),
 }
 
 pub struct ShroomServerSession<H: ShroomSessionHandler> {
     cfg: Arc<ShroomServerConfig>,
-    session_rx: FramedPipeReceiver,
-    pending_ping: bool,
+    session_rx: PriorityReceiver,
+    send_batch: Vec<Bytes>,
+    rate_limiter: RateLimiter,
+    shutdown: Option<CancellationToken>,
+    packets_read: u64,
+    /// Packet which is delayed by the rate limit
+    delayed: Option<ShroomPacket>,
     ctx: ShroomContext<H>,
 }
 
@@ -46,22 +104,37 @@ where
 {
     pub fn new(
         cfg: Arc<ShroomServerConfig>,
-        session_rx: FramedPipeReceiver,
-        ctx: ShroomContext<H>,
+        session_rx: PriorityReceiver,
+        mut ctx: ShroomContext<H>,
     ) -> Self {
+        // Covers the writes of the handler aswell
+        ctx.session.set_write_timeout(cfg.write_stall_timeout);
         Self {
+            rate_limiter: RateLimiter::new(&cfg.rate_limit),
             cfg,
             session_rx,
-            pending_ping: false,
+            send_batch: Vec::new(),
+            shutdown: None,
+            packets_read: 0,
+            delayed: None,
             ctx,
         }
     }
+
+    /// Sets the shutdown token of the server, which is used to tell a server shutdown
+    /// apart from a cancelled session
+    pub fn with_shutdown_token(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     /// Handle migration by finishing the handler and then closing the session
     /// after the migration delay
-    async fn finish(self, migrate: bool) -> Result<(), H::Error> {
-        log::trace!("Session closing(migrate={migrate})");
+    async fn finish(self, reason: DisconnectReason<H::Error>) -> Result<(), H::Error> {
+        log::trace!("Session closing(reason={reason:?})");
+        let migrate = reason.is_migrating();
         let ShroomContext { state, session, .. } = self.ctx;
-        state.finish(migrate).await?;
+        state.finish(reason).await?;
         if migrate {
             // Socket has to be kept open cause the client doesn't support
             // reading a packet when the socket is closed
@@ -72,85 +145,240 @@ where
     }
 
     /// Handle the next ping
-    async fn handle_ping_tick(&mut self) -> Result<(), H::Error> {
+    async fn handle_ping_tick(&mut self) -> NetResult<()> {
         // Check if previous ping was responded
-        if self.pending_ping {
+        if self.ctx.latency.is_ping_pending() {
             log::trace!("Ping Timeout");
-            return Err(NetError::PingTimeout.into());
+            return Err(NetError::PingTimeout);
         }
 
         // Elsewise send a new ping packet
-        self.pending_ping = true;
         self.ctx
             .session
             .send_packet(self.cfg.ping_packet.as_ref())
             .await?;
+        self.ctx.latency.ping_sent(tokio::time::Instant::now());
         Ok(())
     }
 
+    /// Applies the missed frame policy, after the session pipe dropped frames
+    fn handle_missed_frame(&self) -> NetResult<()> {
+        match self.cfg.session_pipe.missed_frame_policy {
+            MissedFramePolicy::Disconnect => Err(NetError::MissedFrame),
+            MissedFramePolicy::LogAndContinue => {
+                log::warn!("Session pipe missed frames");
+                Ok(())
+            }
+        }
+    }
+
     /// Handle incoming pong
     fn handle_pong(&mut self) {
-        // Reset flag
-        self.pending_ping = false;
+        if let Some(rtt) = self.ctx.latency.pong_received(tokio::time::Instant::now()) {
+            log::trace!("Pong RTT: {rtt:?}");
+        }
+    }
+
+    /// Applies the rate limit to the incoming packet
+    fn check_rate_limit(&mut self, pkt: &ShroomPacket) -> NetResult<RateLimitAction> {
+        if !self.rate_limiter.is_enabled() {
+            return Ok(RateLimitAction::Handle);
+        }
+
+        let opcode = pkt.read_opcode().ok();
+        // A dropped pong would end the session with a ping timeout
+        if opcode.is_some() && opcode == self.cfg.pong_opcode {
+            return Ok(RateLimitAction::Handle);
+        }
+
+        let Err(wait) = self.rate_limiter.try_acquire(opcode) else {
+            return Ok(RateLimitAction::Handle);
+        };
+
+        match self.rate_limiter.policy() {
+            RateLimitPolicy::Drop => {
+                log::trace!("Rate limit: dropped packet with opcode {opcode:?}");
+                Ok(RateLimitAction::Drop)
+            }
+            // The bucket never refills, which is rejected by the config validation
+            RateLimitPolicy::Delay => tokio::time::Instant::now()
+                .checked_add(wait)
+                .map(RateLimitAction::Delay)
+                .ok_or(NetError::RateLimited(opcode)),
+            RateLimitPolicy::Disconnect => Err(NetError::RateLimited(opcode)),
+        }
+    }
+
+    /// Converts an error of the handler, an error after the handler started a migration
+    /// like `NetError::Migrated` still ends the session with a migration
+    fn handler_error(&self, err: H::Error) -> DisconnectReason<H::Error> {
+        if self.ctx.is_migrating() {
+            log::debug!("Handler error while migrating: {err:?}");
+            DisconnectReason::Migration
+        } else {
+            DisconnectReason::HandlerError(err)
+        }
+    }
+
+    /// Handles a packet, which was read from the session.
+    /// A packet delayed by the rate limit is stored and `delay` is set to the time,
+    /// when It's handled again. Returns the reason, If the session should be closed
+    async fn handle_read(
+        &mut self,
+        p: ShroomPacket,
+        read_idle: std::pin::Pin<&mut tokio::time::Sleep>,
+        delay: std::pin::Pin<&mut tokio::time::Sleep>,
+    ) -> Result<Option<DisconnectReason<H::Error>>, DisconnectReason<H::Error>> {
+        // Only admitted packets count as activity, pongs don't either
+        let read_idle_timeout = self.cfg.read_idle_timeout;
+        let reset_idle = |read_idle: std::pin::Pin<&mut tokio::time::Sleep>| {
+            if let Some(timeout) = read_idle_timeout {
+                read_idle.reset(tokio::time::Instant::now() + timeout);
+            }
+        };
+
+        match self.check_rate_limit(&p)? {
+            RateLimitAction::Handle => {}
+            RateLimitAction::Drop => return Ok(None),
+            RateLimitAction::Delay(deadline) => {
+                delay.reset(deadline);
+                self.delayed = Some(p);
+                return Ok(None);
+            }
+        }
+
+        // The response of a pending request is passed to the request instead
+        let Some(p) = self.ctx.requests.try_fill(p) else {
+            reset_idle(read_idle);
+            return Ok(None);
+        };
+
+        let res = self.ctx.handle_packet(p).await;
+        let res = res.map_err(|err| self.handler_error(err))?;
+        // Handling the handle result
+        match res {
+            SessionHandleResult::Migrate => return Ok(Some(DisconnectReason::Migration)),
+            SessionHandleResult::Pong => self.handle_pong(),
+            SessionHandleResult::Ok => reset_idle(read_idle),
+        }
+        Ok(None)
     }
 
-    async fn exec_loop(&mut self) -> Result<bool, H::Error> {
+    /// Runs the session until It ends, net errors are converted into
+    /// the matching `DisconnectReason`
+    async fn exec_loop(
+        &mut self,
+    ) -> Result<DisconnectReason<H::Error>, DisconnectReason<H::Error>> {
+        self.ctx.session_handle.set_session_task();
         let mut ping_interval = tokio::time::interval(self.cfg.ping_interval);
+        let read_idle_timeout = self.cfg.read_idle_timeout;
+        let read_idle = tokio::time::sleep(read_idle_timeout.unwrap_or(Duration::MAX));
+        tokio::pin!(read_idle);
+        let delay = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(delay);
 
         loop {
+            // Packets, which were read while the handler awaited a response, are handled first,
+            // unless a packet is delayed by the rate limit
+            if self.delayed.is_none() {
+                if let Some(p) = self.ctx.deferred.pop_front() {
+                    self.packets_read += 1;
+                    let res = self
+                        .handle_read(p, read_idle.as_mut(), delay.as_mut())
+                        .await;
+                    if let Some(reason) = res? {
+                        return Ok(reason);
+                    }
+                    continue;
+                }
+            }
+
+            // No packets are read while a packet is delayed, the other branches keep running
+            let delayed = self.delayed.is_some();
             tokio::select! {
                 biased;
                 // Handle next incoming packet
-                p =  self.ctx.session.read_packet() => {
-                    let res = H::handle_packet(&mut self.ctx, p?).await?;
-                    // Handling the handle result
-                    match res {
-                        SessionHandleResult::Migrate => {
-                            return Ok(true);
-                        },
-                        SessionHandleResult::Pong => {
-                            self.handle_pong();
-                        },
-                        SessionHandleResult::Ok => ()
+                p = self.ctx.session.read_packet(), if !delayed => {
+                    let p = p?;
+                    self.packets_read += 1;
+                    let res = self.handle_read(p, read_idle.as_mut(), delay.as_mut()).await;
+                    if let Some(reason) = res? {
+                        return Ok(reason);
+                    }
+                },
+                // Handle the delayed packet, once the rate limit allows It
+                _ = &mut delay, if delayed => {
+                    let p = self.delayed.take().expect("delayed packet");
+                    let res = self.handle_read(p, read_idle.as_mut(), delay.as_mut()).await;
+                    if let Some(reason) = res? {
+                        return Ok(reason);
                     }
                 },
                 _ = ping_interval.tick() => {
                     self.handle_ping_tick().await?;
                 },
+                // Nothing is read while a packet is delayed, so the idle time doesn't apply
+                _ = &mut read_idle, if read_idle_timeout.is_some() && !delayed => {
+                    log::trace!("Read idle timeout");
+                    return Err(NetError::ReadIdleTimeout.into());
+                },
                 //Handle external Session packets
                 p = self.session_rx.next() => {
-                    // note tx is never dropped, so there'll be always a packet here
-                    // TODO handle error here
-                    let p = p.expect("Session packet").unwrap();
-                    self.ctx.session.send_packet(&p).await?;
+                    // The context keeps a sender, so the pipe only ends If the session is torn down
+                    match p {
+                        Some(Ok(p)) => self.send_batch.push(p),
+                        Some(Err(_)) => {
+                            self.handle_missed_frame()?;
+                            continue;
+                        }
+                        None => break Ok(DisconnectReason::Cancelled),
+                    }
+                    // Send the pending frames of the pipe with the same flush, in priority order
+                    if self.session_rx.try_drain(MAX_SEND_BATCH - 1, &mut self.send_batch).is_err() {
+                        if let Err(err) = self.handle_missed_frame() {
+                            self.send_batch.clear();
+                            return Err(err.into());
+                        }
+                    }
+                    let res = self.ctx.session.send_packets(self.send_batch.iter()).await;
+                    self.send_batch.clear();
+                    res?;
                 },
                 msg = H::poll_msg(&mut self.ctx.state) => {
-                    H::handle_msg(&mut self.ctx, msg?).await?;
+                    let msg = msg.map_err(|err| self.handler_error(err))?;
+                    let res = H::handle_msg(&mut self.ctx, msg).await;
+                    res.map_err(|err| self.handler_error(err))?;
                 },
                 _ = self.ctx.session_handle.ct.cancelled() => {
-                    break Ok(false);
+                    let shutdown = self.shutdown.as_ref().is_some_and(|ct| ct.is_cancelled());
+                    break Ok(if shutdown {
+                        DisconnectReason::ServerShutdown
+                    } else {
+                        DisconnectReason::Cancelled
+                    });
                 },
 
             };
         }
     }
 
-    pub async fn exec(mut self) -> Result<(), H::Error> {
-        let res = self.exec_loop().await;
+    pub async fn exec(self) -> Result<(), H::Error> {
+        self.exec_with_stats(&mut SessionStats::default()).await
+    }
 
-        match res {
-            Ok(true) => {
-                self.finish(true).await?;
-            }
-            Ok(false) => {
-                self.finish(false).await?;
-            }
-            Err(e) => {
-                log::error!("Session error: {e:?}");
-                self.finish(false).await?;
-            }
+    /// Executes the session and updates the `stats` before the handler is finished
+    pub async fn exec_with_stats(mut self, stats: &mut SessionStats) -> Result<(), H::Error> {
+        let reason = self.exec_loop().await.unwrap_or_else(|reason| reason);
+        if reason.is_error() {
+            log::error!("Session error: {reason:?}");
         }
-        Ok(())
+
+        stats.started = true;
+        stats.outcome = SessionOutcome::Disconnected(reason.kind());
+        stats.packets_read = self.packets_read;
+        stats.latency = self.ctx.latency.clone();
+        stats.migrated = reason.is_migrating();
+        self.finish(reason).await
     }
 }
 
@@ -165,6 +393,21 @@ pub struct ShroomServerConfig {
     pub ping_packet: ShroomPacket,
     /// Ping interval
     pub ping_interval: Duration,
+    /// Opcode of the pong, which is exempt from the rate limit
+    pub pong_opcode: Option<u16>,
+    /// Closes the session, If no packet except pongs was read within this duration
+    pub read_idle_timeout: Option<Duration>,
+    /// Closes the session, If a write to the client doesn't complete within this duration.
+    /// This covers the pings, the packets of the session handle and the sends of the handler
+    pub write_stall_timeout: Option<Duration>,
+    /// Duration for how long the shutdown waits for the sessions to finish, before aborting them
+    pub shutdown_timeout: Duration,
+    /// Limits for incoming connections, which are checked before the handshake is sent
+    pub conn_limits: ConnectionLimits,
+    /// Limits for the incoming packets of every session
+    pub rate_limit: RateLimitConfig,
+    /// Sizes of the `SharedSessionHandle` pipe and the policy for missed frames
+    pub session_pipe: PipeConfig,
 }
 
 /// Server which can host multiple Session
@@ -179,6 +422,8 @@ where
     handshake_gen: H,
     make_handler: MH,
     handles: Vec<ShroomSessionHandle<MH::Handler>>,
+    shutdown: CancellationToken,
+    admission: Admission,
 }
 
 impl<MH, H> ShroomServer<MH, H>
@@ -188,13 +433,72 @@ where
     MH::Handler: Send,
 {
     /// Creates a new server with the given config
+    ///
+    /// # Panics
+    /// If the config is invalid, `try_new` returns the error instead
     pub fn new(cfg: ShroomServerConfig, handshake_gen: H, make_handler: MH) -> Self {
-        Self {
+        Self::try_new(cfg, handshake_gen, make_handler).expect("Invalid server config")
+    }
+
+    /// Creates a new server with the given config, fails If the config is invalid
+    pub fn try_new(cfg: ShroomServerConfig, handshake_gen: H, make_handler: MH) -> NetResult<Self> {
+        cfg.rate_limit.validate()?;
+        Ok(Self {
+            admission: Admission::new(cfg.conn_limits.clone()),
             cfg: Arc::new(cfg),
             handshake_gen,
             make_handler,
             handles: Vec::new(),
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Sets a filter, which is checked for every incoming connection
+    pub fn with_accept_filter(mut self, filter: impl AcceptFilter + 'static) -> Self {
+        self.admission.set_filter(filter);
+        self
+    }
+
+    /// Number of active connections
+    pub fn active_connections(&self) -> usize {
+        self.admission.active_connections()
+    }
+
+    /// Cancels all sessions and waits up to the shutdown timeout for them to finish,
+    /// sessions which are still running after the timeout are aborted
+    pub async fn shutdown(&mut self) -> ShutdownSummary<MH::Error> {
+        // Cancels the token of every session
+        self.shutdown.cancel();
+        // Reset the token, so the server can be re-used
+        self.shutdown = CancellationToken::new();
+
+        let deadline = tokio::time::Instant::now() + self.cfg.shutdown_timeout;
+        let mut summary = ShutdownSummary::default();
+        for mut session in self.handles.drain(..) {
+            let res = match tokio::time::timeout_at(deadline, &mut session.handle).await {
+                Ok(res) => res,
+                Err(_) => {
+                    session.handle.abort();
+                    session.handle.await
+                }
+            };
+
+            match res {
+                Ok(Ok(())) => summary.finished += 1,
+                Ok(Err(err)) => summary.errors.push(err),
+                Err(err) if err.is_panic() => summary.panicked += 1,
+                Err(_) => summary.aborted += 1,
+            }
         }
+
+        log::info!(
+            "Server shutdown: finished={}, errors={}, panicked={}, aborted={}",
+            summary.finished,
+            summary.errors.len(),
+            summary.panicked,
+            summary.aborted
+        );
+        summary
     }
 
     /// Removes all closed sesison handles
@@ -204,8 +508,6 @@ where
 
     /// Adds a handle
     fn add_handle(&mut self, handle: ShroomSessionHandle<MH::Handler>) {
-        // TODO: there should be an upper limit for active connections
-
         self.remove_closed_handles();
         self.handles.push(handle);
     }
@@ -217,7 +519,7 @@ where
     MH: MakeServerSessionHandler + Send + Clone + 'static,
     MH::Error: From<io::Error> + Send + 'static,
     MH::Handler: Send + 'static,
-    MH::Transport: Send + Unpin + 'static,
+    MH::Transport: PeerAddr + Send + Unpin + 'static,
 {
     /// Spawn a incoming `io` Transport
     fn spawn(
@@ -225,30 +527,70 @@ where
         cfg: Arc<ShroomServerConfig>,
         mut mk: MH,
         handshake: Handshake,
+        ct: CancellationToken,
+        shutdown: CancellationToken,
+        admission: Result<ConnectionGuard, RejectReason>,
     ) -> ShroomSessionHandle<MH::Handler> {
+        let session_ct = ct.clone();
         // Spawn the future
         let handle = tokio::spawn(async move {
+            let accepted_at = tokio::time::Instant::now();
+            let mut stats = SessionStats {
+                peer_addr: io.peer_addr(),
+                ..Default::default()
+            };
+
             // Using a block here so we can capture the result and log It later
-            let res = async move {
+            let res = async {
+                // Keep the connection slot until the session ends
+                let _conn_guard = match admission {
+                    Ok(guard) => guard,
+                    Err(reason) => {
+                        log::debug!("Rejected connection from {:?}: {reason}", stats.peer_addr);
+                        stats.outcome = SessionOutcome::RejectedByLimits(reason);
+                        return Ok(());
+                    }
+                };
+
+                if !mk.on_accept(stats.peer_addr).await {
+                    log::debug!("Connection from {:?} rejected by handler", stats.peer_addr);
+                    stats.outcome = SessionOutcome::RejectedByHandler;
+                    return Ok(());
+                }
+
                 // Initialize the session with the handshake
-                let session =
-                    ShroomSession::initialize_server_session(io, cfg.crypto_ctx.clone(), handshake)
-                        .await?;
+                let session = ShroomSession::initialize_server_session(
+                    io,
+                    cfg.crypto_ctx.clone(),
+                    handshake.clone(),
+                )
+                .await
+                .inspect_err(|_| stats.outcome = SessionOutcome::HandshakeError)?;
+                mk.on_handshake_sent(&handshake).await;
 
                 // Create the shared session handle and context
-                let (session_handle, session_rx) = SharedSessionHandle::new();
+                let (session_handle, session_rx) =
+                    SharedSessionHandle::with_pipe_config(session_ct, &cfg.session_pipe);
+                let session_handle = session_handle.with_str_codec(session.str_codec());
 
                 // Create the session handler
-                let ctx = mk.make_handler(session, session_handle).await?;
+                let ctx = mk
+                    .make_handler(session, session_handle.clone())
+                    .await
+                    .inspect_err(|_| stats.outcome = SessionOutcome::MakeHandlerError)?;
+                mk.on_session_started(&session_handle).await;
 
                 // Create the session and execute It
-                let server_session = ShroomServerSession::new(cfg, session_rx, ctx);
+                let server_session =
+                    ShroomServerSession::new(cfg, session_rx, ctx).with_shutdown_token(shutdown);
 
-                server_session.exec().await
+                server_session.exec_with_stats(&mut stats).await
             };
 
             // Await the block
             let res = res.await;
+            stats.duration = accepted_at.elapsed();
+            mk.on_session_ended(stats).await;
             // Print the error If there's one
             if let Err(ref err) = res {
                 log::error!("Session error: {:?}", err);
@@ -260,22 +602,29 @@ where
 
         ShroomSessionHandle {
             handle,
+            ct,
             _handler: PhantomData,
         }
     }
 
     /// Handles an incoming `io` Transport
-    fn handle_incoming(&mut self, io: MH::Transport)
-    where
-        MH: Send + Clone + 'static,
-        MH::Error: From<io::Error> + Send + 'static,
-        MH::Handler: Send + 'static,
-        MH::Transport: Send + Unpin + 'static,
-    {
+    fn handle_incoming(&mut self, io: MH::Transport) {
+        // Reject the connection before the handshake is written,
+        // the session task still calls the hooks for the rejected connection
+        let admission = self.admission.admit(io.peer_addr());
+
         // Generate the handshake here
         let handshake = self.handshake_gen.generate_handshake();
         // Spawn the connection
-        let handle = Self::spawn(io, self.cfg.clone(), self.make_handler.clone(), handshake);
+        let handle = Self::spawn(
+            io,
+            self.cfg.clone(),
+            self.make_handler.clone(),
+            handshake,
+            self.shutdown.child_token(),
+            self.shutdown.clone(),
+            admission,
+        );
         // Add the handle to the interal collection
         self.add_handle(handle);
     }
@@ -302,6 +651,36 @@ where
 
         Ok(())
     }
+
+    /// Run the server on an incoming Stream of Transports until the `signal` completes,
+    /// then no more transports are accepted and the active sessions are shut down
+    pub async fn serve_with_shutdown<S, F>(
+        &mut self,
+        mut io: S,
+        signal: F,
+    ) -> Result<ShutdownSummary<MH::Error>, MH::Error>
+    where
+        S: Stream<Item = std::io::Result<MH::Transport>> + Unpin,
+        F: Future<Output = ()>,
+    {
+        tokio::pin!(signal);
+        loop {
+            tokio::select! {
+                _ = &mut signal => break,
+                io = io.next() => match io {
+                    Some(Ok(io)) => self.handle_incoming(io),
+                    Some(Err(err)) => {
+                        // Sessions still have to be finished
+                        self.shutdown().await;
+                        return Err(err.into());
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        Ok(self.shutdown().await)
+    }
 }
 
 impl<MH, H> ShroomServer<MH, H>
@@ -326,4 +705,813 @@ where
         }))
         .await
     }
+
+    /// Serve with the given `addr` via Tcp as Transport until the `signal` completes
+    pub async fn serve_tcp_with_shutdown(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        signal: impl Future<Output = ()>,
+    ) -> Result<ShutdownSummary<MH::Error>, MH::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve_with_shutdown(
+            TcpListenerStream::new(listener).filter(|io| {
+                std::future::ready(match io {
+                    Err(err) if Self::is_connection_error(err) => {
+                        log::trace!("Server Connection error: {}", err);
+                        false
+                    }
+                    _ => true,
+                })
+            }),
+            signal,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::io::DuplexStream;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        crypto::SharedCryptoContext,
+        net::{
+            service::{
+                rate_limit::{RateLimitConfig, RateLimitPolicy, TokenBucketConfig},
+                request::ResponseFuture,
+                BasicHandshakeGenerator, DisconnectReason, HandshakeGenerator, MissedFramePolicy,
+                PipeConfig, SessionHandleResult, SharedSessionHandle, ShroomContext,
+            },
+            ShroomSession,
+        },
+        opcode::WithOpcode,
+        packet::StringCodec,
+        util::priority_pipe::Priority,
+        DecodePacket, NetError, ShroomPacket,
+    };
+
+    use super::{
+        Handshake, MakeServerSessionHandler, SessionOutcome, SessionStats, ShroomServer,
+        ShroomServerConfig, ShroomServerSession, ShroomSessionHandler, ShutdownSummary,
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct MakeHandler {
+        finished: Arc<AtomicUsize>,
+        finish_delay: Duration,
+        reject: bool,
+        events: Arc<parking_lot::Mutex<Vec<&'static str>>>,
+    }
+
+    struct Handler {
+        finished: Arc<AtomicUsize>,
+        finish_delay: Duration,
+        handled: usize,
+        pending: Option<ResponseFuture<RequestResp>>,
+    }
+
+    type RequestReq = WithOpcode<0x16, ()>;
+    type RequestResp = WithOpcode<0x12, u16>;
+
+    #[async_trait::async_trait]
+    impl MakeServerSessionHandler for MakeHandler {
+        type Transport = DuplexStream;
+        type Error = anyhow::Error;
+        type Handler = Handler;
+
+        async fn make_handler(
+            &mut self,
+            sess: ShroomSession<Self::Transport>,
+            handle: SharedSessionHandle,
+        ) -> Result<ShroomContext<Self::Handler>, Self::Error> {
+            let handler = Handler {
+                finished: self.finished.clone(),
+                finish_delay: self.finish_delay,
+                handled: 0,
+                pending: None,
+            };
+            Ok(ShroomContext::new(sess, handler, handle))
+        }
+
+        async fn on_accept(&mut self, _peer_addr: Option<SocketAddr>) -> bool {
+            self.events.lock().push("accept");
+            !self.reject
+        }
+
+        async fn on_handshake_sent(&mut self, _handshake: &Handshake) {
+            self.events.lock().push("handshake_sent");
+        }
+
+        async fn on_session_started(&mut self, _handle: &SharedSessionHandle) {
+            self.events.lock().push("session_started");
+        }
+
+        async fn on_session_ended(&mut self, stats: SessionStats) {
+            self.events.lock().push(match stats.outcome {
+                SessionOutcome::Disconnected(_) => "session_ended",
+                SessionOutcome::RejectedByHandler => "rejected",
+                SessionOutcome::RejectedByLimits(_) => "rejected_by_limits",
+                _ => "error",
+            });
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ShroomSessionHandler for Handler {
+        type Transport = DuplexStream;
+        type Error = anyhow::Error;
+        type Msg = RequestResp;
+
+        async fn handle_packet(
+            ctx: &mut ShroomContext<Self>,
+            packet: ShroomPacket,
+        ) -> Result<SessionHandleResult, Self::Error> {
+            // Opcode 0x10 starts a request, opcode 0x14 floods the client,
+            // opcode 0x17 fails after starting a migration, opcode 0x18 fills the pipe
+            // and opcode 0x19 awaits a request in the handler
+            match packet.read_opcode()? {
+                0x2 => {
+                    ctx.handled += 1;
+                    return Ok(SessionHandleResult::Pong);
+                }
+                0x10 => {
+                    let pending = ctx
+                        .send_request(RequestReq::default(), Duration::from_secs(10))
+                        .await?;
+                    ctx.pending = Some(pending);
+                }
+                0x19 => {
+                    let resp: RequestResp = ctx
+                        .request(RequestReq::default(), Duration::from_secs(10))
+                        .await?;
+                    ctx.send(WithOpcode::<0x13, u16>(resp.0 * 2)).await?;
+                }
+                0x14 => loop {
+                    ctx.send(WithOpcode::<0x15, u64>(0)).await?;
+                },
+                0x17 => {
+                    ctx.set_migrate(true);
+                    return Err(NetError::Migrated.into());
+                }
+                0x18 => {
+                    for _ in 0..2 {
+                        ctx.session_handle.send_pkt([0; 48]).await?;
+                    }
+                }
+                _ => ctx.handled += 1,
+            }
+            Ok(SessionHandleResult::Ok)
+        }
+
+        async fn poll_msg(&mut self) -> Result<Self::Msg, Self::Error> {
+            let Some(pending) = self.pending.as_mut() else {
+                return futures::future::pending().await;
+            };
+            let resp = pending.await;
+            self.pending = None;
+            Ok(resp?)
+        }
+
+        // Sends the doubled response back
+        async fn handle_msg(
+            ctx: &mut ShroomContext<Self>,
+            msg: Self::Msg,
+        ) -> Result<(), Self::Error> {
+            ctx.send(WithOpcode::<0x13, u16>(msg.0 * 2)).await?;
+            Ok(())
+        }
+
+        async fn finish(self, _reason: DisconnectReason<Self::Error>) -> Result<(), Self::Error> {
+            tokio::time::sleep(self.finish_delay).await;
+            self.finished.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn test_cfg() -> ShroomServerConfig {
+        ShroomServerConfig {
+            crypto_ctx: SharedCryptoContext::default(),
+            migrate_delay: Duration::from_secs(1),
+            ping_packet: ShroomPacket::from_data(vec![0x11, 0x00].into()),
+            ping_interval: Duration::from_secs(3600),
+            pong_opcode: Some(0x2),
+            read_idle_timeout: None,
+            write_stall_timeout: None,
+            shutdown_timeout: Duration::from_secs(5),
+            conn_limits: Default::default(),
+            rate_limit: Default::default(),
+            session_pipe: Default::default(),
+        }
+    }
+
+    /// Creates a server session with the config and the connected client session
+    async fn session_pair(
+        cfg: ShroomServerConfig,
+        buf_size: usize,
+    ) -> (ShroomServerSession<Handler>, ShroomSession<DuplexStream>) {
+        let (client, server) = tokio::io::duplex(buf_size);
+        let handshake = BasicHandshakeGenerator::v83().generate_handshake();
+        let (server, client) = tokio::join!(
+            ShroomSession::initialize_server_session(
+                server,
+                SharedCryptoContext::default(),
+                handshake
+            ),
+            ShroomSession::initialize_client_session(client, SharedCryptoContext::default())
+        );
+
+        let (session_handle, session_rx) =
+            SharedSessionHandle::with_pipe_config(CancellationToken::new(), &cfg.session_pipe);
+        let handler = Handler {
+            finished: Arc::default(),
+            finish_delay: Duration::ZERO,
+            handled: 0,
+            pending: None,
+        };
+        let ctx = ShroomContext::new(server.unwrap(), handler, session_handle);
+        (
+            ShroomServerSession::new(Arc::new(cfg), session_rx, ctx),
+            client.unwrap().0,
+        )
+    }
+
+    /// Connects `n` clients to the server and shuts It down afterwards
+    async fn connect_and_shutdown(mk: MakeHandler, n: usize) -> ShutdownSummary<anyhow::Error> {
+        connect_and_shutdown_with(test_cfg(), mk, n).await
+    }
+
+    async fn connect_and_shutdown_with(
+        cfg: ShroomServerConfig,
+        mk: MakeHandler,
+        n: usize,
+    ) -> ShutdownSummary<anyhow::Error> {
+        let mut server = ShroomServer::new(cfg, BasicHandshakeGenerator::v83(), mk);
+
+        let (conn_tx, conn_rx) = futures::channel::mpsc::unbounded();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let mut clients = Vec::new();
+        for _ in 0..n {
+            let (client, server) = tokio::io::duplex(4096);
+            conn_tx.unbounded_send(Ok(server)).unwrap();
+            clients.push(client);
+        }
+
+        let clients = tokio::spawn(async move {
+            let mut sessions = Vec::new();
+            for client in clients {
+                // Rejected clients don't receive a handshake
+                if let Ok((sess, _)) =
+                    ShroomSession::initialize_client_session(client, SharedCryptoContext::default())
+                        .await
+                {
+                    sessions.push(sess);
+                }
+            }
+            shutdown_tx.send(()).unwrap();
+            sessions
+        });
+
+        let summary = server
+            .serve_with_shutdown(conn_rx, async move {
+                shutdown_rx.await.unwrap();
+            })
+            .await
+            .unwrap();
+        drop(clients.await.unwrap());
+        summary
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn graceful_shutdown() {
+        let mk = MakeHandler {
+            finish_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let summary = connect_and_shutdown(mk.clone(), 3).await;
+        assert!(summary.is_clean());
+        assert_eq!(summary.finished, 3);
+        assert_eq!(mk.finished.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_deadline() {
+        let mk = MakeHandler {
+            finish_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let summary = connect_and_shutdown(mk.clone(), 2).await;
+        assert_eq!(summary.aborted, 2);
+        assert_eq!(mk.finished.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifecycle_hooks() {
+        let mk = MakeHandler::default();
+        connect_and_shutdown(mk.clone(), 1).await;
+        assert_eq!(
+            *mk.events.lock(),
+            [
+                "accept",
+                "handshake_sent",
+                "session_started",
+                "session_ended"
+            ]
+        );
+
+        let mk = MakeHandler {
+            reject: true,
+            ..Default::default()
+        };
+        let summary = connect_and_shutdown(mk.clone(), 1).await;
+        assert_eq!(summary.finished, 1);
+        assert_eq!(*mk.events.lock(), ["accept", "rejected"]);
+        assert_eq!(mk.finished.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifecycle_hooks_limits() {
+        let mut cfg = test_cfg();
+        cfg.conn_limits.max_connections = Some(1);
+        let mk = MakeHandler::default();
+        let summary = connect_and_shutdown_with(cfg, mk.clone(), 2).await;
+        assert_eq!(summary.finished, 2);
+
+        // The rejected connection skips `on_accept`, but still ends
+        let mut events = mk.events.lock().clone();
+        events.sort_unstable();
+        assert_eq!(
+            events,
+            [
+                "accept",
+                "handshake_sent",
+                "rejected_by_limits",
+                "session_ended",
+                "session_started"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifecycle_hooks_no_peer_addr() {
+        // The in-memory transport has no address, so the per IP limit rejects It
+        let mut cfg = test_cfg();
+        cfg.conn_limits.max_connections_per_ip = Some(4);
+        let mk = MakeHandler::default();
+        let summary = connect_and_shutdown_with(cfg, mk.clone(), 1).await;
+        assert_eq!(summary.finished, 1);
+        assert_eq!(*mk.events.lock(), ["rejected_by_limits"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_idle_timeout() {
+        let mut cfg = test_cfg();
+        cfg.read_idle_timeout = Some(Duration::from_secs(10));
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+
+        let client = tokio::spawn(async move {
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            client
+        });
+
+        let start = tokio::time::Instant::now();
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ReadIdleTimeout));
+        // The second packet resets the timer
+        assert!(start.elapsed() >= Duration::from_secs(15));
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_stall_timeout() {
+        let mut cfg = test_cfg();
+        cfg.write_stall_timeout = Some(Duration::from_secs(10));
+        let (mut sess, _client) = session_pair(cfg, 64).await;
+
+        // The client never reads, so the transport buffer fills up
+        let handle = sess.ctx.session_handle.clone();
+        for _ in 0..4 {
+            handle.try_send_pkt([0; 32]).unwrap();
+        }
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::WriteStallTimeout));
+
+        // Writes of the handler are covered aswell
+        let mut cfg = test_cfg();
+        cfg.write_stall_timeout = Some(Duration::from_secs(10));
+        let (mut sess, mut client) = session_pair(cfg, 64).await;
+        client.send_packet(&[0x14, 0x0]).await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        let reason = sess.exec_loop().await.unwrap_err();
+        let DisconnectReason::HandlerError(err) = reason else {
+            panic!("Expected handler error");
+        };
+        assert!(matches!(
+            err.downcast().unwrap(),
+            NetError::WriteStallTimeout
+        ));
+        assert!(start.elapsed() >= Duration::from_secs(10));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn disconnect_reasons() {
+        let (mut sess, client) = session_pair(test_cfg(), 4096).await;
+        drop(client);
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+
+        let (mut sess, _client) = session_pair(test_cfg(), 4096).await;
+        sess.ctx.session_handle.cancel();
+        let reason = sess.exec_loop().await.unwrap();
+        assert!(matches!(reason, DisconnectReason::Cancelled));
+
+        let (sess, _client) = session_pair(test_cfg(), 4096).await;
+        let shutdown = CancellationToken::new();
+        let mut sess = sess.with_shutdown_token(shutdown.clone());
+        shutdown.cancel();
+        sess.ctx.session_handle.cancel();
+        let reason = sess.exec_loop().await.unwrap();
+        assert!(matches!(reason, DisconnectReason::ServerShutdown));
+
+        // An error after starting the migration still migrates
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+        client.send_packet(&[0x17, 0x0]).await.unwrap();
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::Migration));
+    }
+
+    #[tokio::test]
+    async fn request() {
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+
+        let client = tokio::spawn(async move {
+            client.send_packet(&[0x10, 0x0]).await.unwrap();
+            let req = read_skip_pings(&mut client).await;
+            assert_eq!(req.read_opcode().unwrap(), 0x16);
+
+            // Unrelated packets are still handled, before the reply arrives
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            client
+                .send_encode_packet(WithOpcode::<0x12, u16>(21))
+                .await
+                .unwrap();
+
+            let resp = read_skip_pings(&mut client).await;
+            let mut pr = resp.into_reader();
+            assert_eq!(pr.read_opcode::<u16>().unwrap(), 0x13);
+            assert_eq!(pr.read_u16().unwrap(), 42);
+        });
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert_eq!(sess.ctx.handled, 2);
+        client.await.unwrap();
+    }
+
+    /// Reads the next packet, which is not a ping
+    async fn read_skip_pings(client: &mut ShroomSession<DuplexStream>) -> ShroomPacket {
+        loop {
+            let pkt = client.read_packet().await.unwrap();
+            if pkt.read_opcode().unwrap() != 0x11 {
+                return pkt;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_keeps_session_running() {
+        let mut cfg = test_cfg();
+        cfg.ping_interval = Duration::from_secs(5);
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+        let handle = sess.ctx.session_handle.clone();
+
+        let client = tokio::spawn(async move {
+            client.send_packet(&[0x10, 0x0]).await.unwrap();
+
+            // The pipe is drained and pings are sent, while the request is pending
+            let (mut frame, mut pings) = (false, 0);
+            while !frame || pings < 2 {
+                let pkt = client.read_packet().await.unwrap();
+                match pkt.read_opcode().unwrap() {
+                    0x11 => {
+                        pings += 1;
+                        client.send_packet(&[0x2, 0x0]).await.unwrap();
+                    }
+                    0x16 => handle.try_send_pkt([0x20, 0x0]).unwrap(),
+                    0x20 => frame = true,
+                    op => panic!("Unexpected opcode: {op:X}"),
+                }
+            }
+
+            client
+                .send_encode_packet(WithOpcode::<0x12, u16>(21))
+                .await
+                .unwrap();
+            let resp = read_skip_pings(&mut client).await;
+            let mut pr = resp.into_reader();
+            assert_eq!(pr.read_opcode::<u16>().unwrap(), 0x13);
+            assert_eq!(pr.read_u16().unwrap(), 42);
+        });
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert!(sess.ctx.latency().smoothed_rtt().is_some());
+        // Includes the pong and the response
+        assert_eq!(sess.packets_read, 4);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_in_handler() {
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+
+        let client = tokio::spawn(async move {
+            // Both requests wait for opcode 0x12, the first one is polled by the session
+            for op in [0x10, 0x19] {
+                client.send_packet(&[op, 0x0]).await.unwrap();
+                let req = read_skip_pings(&mut client).await;
+                assert_eq!(req.read_opcode().unwrap(), 0x16);
+            }
+
+            // The packet is handled after the awaiting handler returned
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            for v in [21u16, 22] {
+                client
+                    .send_encode_packet(WithOpcode::<0x12, u16>(v))
+                    .await
+                    .unwrap();
+            }
+
+            // The handler replies first, the polled request afterwards
+            for v in [44, 42] {
+                let resp = read_skip_pings(&mut client).await;
+                let mut pr = resp.into_reader();
+                assert_eq!(pr.read_opcode::<u16>().unwrap(), 0x13);
+                assert_eq!(pr.read_u16().unwrap(), v);
+            }
+        });
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert_eq!(sess.ctx.handled, 1);
+        client.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_timeout() {
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+        client.send_packet(&[0x10, 0x0]).await.unwrap();
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        let DisconnectReason::HandlerError(err) = reason else {
+            panic!("Expected handler error");
+        };
+        assert!(matches!(
+            err.downcast().unwrap(),
+            NetError::RequestTimeout(0x12)
+        ));
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn send_from_session_task() {
+        let mut cfg = test_cfg();
+        cfg.session_pipe.normal.buf_cap = 64;
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+        client.send_packet(&[0x18, 0x0]).await.unwrap();
+
+        // Waiting for capacity fails, because the pipe is only drained after the handler returned
+        let session = tokio::spawn(async move { sess.exec_loop().await.map(|_| ()) });
+        let reason = session.await.unwrap().unwrap_err();
+        assert!(matches!(reason, DisconnectReason::HandlerError(_)));
+        drop(client);
+    }
+
+    /// Runs a session with the rate limit, which receives `n` packets and a pong
+    async fn run_rate_limited(
+        rate_limit: RateLimitConfig,
+        n: usize,
+    ) -> (
+        ShroomServerSession<Handler>,
+        DisconnectReason<anyhow::Error>,
+    ) {
+        let mut cfg = test_cfg();
+        cfg.rate_limit = rate_limit;
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+        for _ in 0..n {
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+        }
+        client.send_packet(&[0x2, 0x0]).await.unwrap();
+        // Keeps the client open, so the pings can be sent while a packet is delayed
+        let client = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(client);
+        });
+
+        let reason = sess.exec_loop().await.unwrap_err();
+        client.await.unwrap();
+        (sess, reason)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_policies() {
+        let bucket = TokenBucketConfig::new(2, 1.0);
+
+        // Packets over the limit are dropped, but not the pong
+        let (sess, reason) =
+            run_rate_limited(RateLimitConfig::default().with_global(bucket), 4).await;
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert_eq!(sess.ctx.handled, 3);
+
+        // Packets over the limit are handled once the bucket refilled
+        let start = tokio::time::Instant::now();
+        let (sess, reason) = run_rate_limited(
+            RateLimitConfig::default()
+                .with_global(bucket)
+                .with_policy(RateLimitPolicy::Delay),
+            4,
+        )
+        .await;
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert_eq!(sess.ctx.handled, 5);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+
+        let (sess, reason) = run_rate_limited(
+            RateLimitConfig::default()
+                .with_global(bucket)
+                .with_policy(RateLimitPolicy::Disconnect),
+            4,
+        )
+        .await;
+        assert!(matches!(reason, DisconnectReason::RateLimited(Some(0x1))));
+        assert_eq!(sess.ctx.handled, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_drop_idle() {
+        let mut cfg = test_cfg();
+        cfg.read_idle_timeout = Some(Duration::from_secs(10));
+        cfg.rate_limit = RateLimitConfig::default().with_global(TokenBucketConfig::new(1, 0.01));
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+
+        let client = tokio::spawn(async move {
+            for _ in 0..30 {
+                client.send_packet(&[0x1, 0x0]).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            client
+        });
+
+        // Dropped packets don't reset the timer
+        let start = tokio::time::Instant::now();
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ReadIdleTimeout));
+        assert!(start.elapsed() < Duration::from_secs(11));
+        assert_eq!(sess.ctx.handled, 1);
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_delay_pipe() {
+        let mut cfg = test_cfg();
+        cfg.rate_limit = RateLimitConfig::default()
+            .with_global(TokenBucketConfig::new(1, 0.1))
+            .with_policy(RateLimitPolicy::Delay);
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+        let handle = sess.ctx.session_handle.clone();
+
+        let start = tokio::time::Instant::now();
+        let client = tokio::spawn(async move {
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            client.send_packet(&[0x1, 0x0]).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            handle.try_send_pkt([0x20, 0x0]).unwrap();
+            loop {
+                let pkt = client.read_packet().await.unwrap();
+                if pkt.read_opcode().unwrap() != 0x11 {
+                    assert_eq!(pkt.as_ref(), &[0x20, 0x0][..]);
+                    return start.elapsed();
+                }
+            }
+        });
+
+        // The pipe is sent, while the second packet waits for a token
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::ClientClosed));
+        assert!(client.await.unwrap() < Duration::from_secs(10));
+        assert!(start.elapsed() >= Duration::from_secs(10));
+        assert_eq!(sess.ctx.handled, 2);
+    }
+
+    #[test]
+    fn invalid_rate_limit() {
+        let mut cfg = test_cfg();
+        cfg.rate_limit = RateLimitConfig::default()
+            .with_global(TokenBucketConfig::new(2, 0.0))
+            .with_policy(RateLimitPolicy::Delay);
+        let server =
+            ShroomServer::try_new(cfg, BasicHandshakeGenerator::v83(), MakeHandler::default());
+        assert!(matches!(server, Err(NetError::InvalidRateLimit(_))));
+    }
+
+    #[tokio::test]
+    async fn pipe_priority() {
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+        let handle = sess.ctx.session_handle.clone();
+        handle
+            .try_send_pkt_with(Priority::Low, [0x23, 0x0])
+            .unwrap();
+        handle
+            .try_send_pkt_with(Priority::Normal, [0x22, 0x0])
+            .unwrap();
+        handle
+            .try_send_pkt_with(Priority::High, [0x21, 0x0])
+            .unwrap();
+
+        let client = tokio::spawn(async move {
+            for op in [0x21, 0x22, 0x23] {
+                let pkt = read_skip_pings(&mut client).await;
+                assert_eq!(pkt.read_opcode().unwrap(), op);
+            }
+        });
+        let session = tokio::spawn(async move { sess.exec_loop().await.map(|_| ()) });
+        client.await.unwrap();
+        assert!(matches!(
+            session.await.unwrap(),
+            Err(DisconnectReason::ClientClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_str_codec() {
+        let (mut sess, mut client) = session_pair(test_cfg(), 4096).await;
+        // The global locale uses cp1252
+        assert_eq!(sess.ctx.str_codec(), StringCodec::Cp1252);
+        let handle = sess.ctx.session_handle.clone();
+        assert_eq!(handle.str_codec(), StringCodec::Cp1252);
+        handle
+            .try_send_encode_pkt(WithOpcode::<0x24, String>("Müller".to_string()))
+            .unwrap();
+
+        let client = tokio::spawn(async move {
+            let pkt = read_skip_pings(&mut client).await;
+            assert_eq!(&pkt.as_ref()[2..], b"\x06\x00M\xFCller");
+            let mut pr = pkt.into_reader();
+            assert_eq!(pr.read_u16().unwrap(), 0x24);
+            assert_eq!(String::decode_packet(&mut pr).unwrap(), "Müller");
+        });
+        let session = tokio::spawn(async move { sess.exec_loop().await.map(|_| ()) });
+        client.await.unwrap();
+        assert!(matches!(
+            session.await.unwrap(),
+            Err(DisconnectReason::ClientClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn missed_frame_policy() {
+        let mut cfg = test_cfg();
+        cfg.session_pipe.normal.buf_cap = 64;
+        let (mut sess, _client) = session_pair(cfg, 4096).await;
+        let handle = sess.ctx.session_handle.clone();
+        handle.try_send_pkt([0; 48]).unwrap();
+        assert!(handle.try_send_pkt([0; 48]).is_err());
+        let reason = sess.exec_loop().await.unwrap_err();
+        assert!(matches!(reason, DisconnectReason::MissedFrame));
+
+        let mut cfg = test_cfg();
+        cfg.session_pipe = PipeConfig {
+            missed_frame_policy: MissedFramePolicy::LogAndContinue,
+            ..Default::default()
+        };
+        cfg.session_pipe.normal.buf_cap = 64;
+        let (mut sess, mut client) = session_pair(cfg, 4096).await;
+        let handle = sess.ctx.session_handle.clone();
+        handle.try_send_pkt([0; 48]).unwrap();
+        assert!(handle.try_send_pkt([0; 48]).is_err());
+        handle.try_send_pkt([1; 8]).unwrap();
+
+        let client = tokio::spawn(async move {
+            assert_eq!(client.read_packet().await.unwrap().as_ref(), &[0; 48][..]);
+            assert_eq!(client.read_packet().await.unwrap().as_ref(), &[1; 8][..]);
+        });
+        let session = tokio::spawn(async move { sess.exec_loop().await.map(|_| ()) });
+        client.await.unwrap();
+        assert!(matches!(
+            session.await.unwrap(),
+            Err(DisconnectReason::ClientClosed)
+        ));
+    }
 }
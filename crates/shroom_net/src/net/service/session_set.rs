@@ -1,35 +1,90 @@
-use indexmap::IndexMap;
+use bytes::BytesMut;
+use indexmap::{IndexMap, IndexSet};
+use std::collections::HashSet;
 use std::hash::Hash;
 use std::sync::RwLock;
 
-use crate::{EncodePacket, HasOpcode, PacketWriter, ShroomPacket};
+use crate::{
+    packet::StringCodec, util::priority_pipe::Priority, EncodePacket, HasOpcode, PacketWriter,
+    ShroomPacket,
+};
 
 use super::SharedSessionHandle;
 
+/// Groups with the members and the groups each session joined,
+/// so removing a session only touches Its own groups
 #[derive(Debug)]
-pub struct SessionSet<Key>(RwLock<IndexMap<Key, SharedSessionHandle>>);
+struct Groups<Key, Group> {
+    members: IndexMap<Group, IndexSet<Key>>,
+    joined: IndexMap<Key, IndexSet<Group>>,
+}
+
+impl<Key, Group> Default for Groups<Key, Group> {
+    fn default() -> Self {
+        Self {
+            members: IndexMap::default(),
+            joined: IndexMap::default(),
+        }
+    }
+}
+
+impl<Key: Hash + Eq, Group: Hash + Eq> Groups<Key, Group> {
+    /// Removes the key from the members of the group, an empty group is removed
+    fn remove_member(&mut self, group: &Group, key: &Key) -> bool {
+        let Some(members) = self.members.get_mut(group) else {
+            return false;
+        };
+        let left = members.shift_remove(key);
+        if members.is_empty() {
+            self.members.shift_remove(group);
+        }
+        left
+    }
+}
+
+/// Set of sessions, which can be joined into named groups like map instances or parties.
+/// The groups lock is always taken before the sessions lock
+#[derive(Debug)]
+pub struct SessionSet<Key, Group = u32> {
+    sessions: RwLock<IndexMap<Key, SharedSessionHandle>>,
+    groups: RwLock<Groups<Key, Group>>,
+}
 
-impl<Key: Hash + Eq> Default for SessionSet<Key> {
+impl<Key: Hash + Eq, Group: Hash + Eq> Default for SessionSet<Key, Group> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<Key: Hash + Eq> SessionSet<Key> {
+impl<Key: Hash + Eq, Group: Hash + Eq> SessionSet<Key, Group> {
     pub fn new() -> Self {
-        Self(RwLock::default())
+        Self {
+            sessions: RwLock::default(),
+            groups: RwLock::default(),
+        }
     }
 
     pub fn add(&self, key: Key, session: SharedSessionHandle) {
-        self.0.write().expect("Session add").insert(key, session);
+        self.sessions
+            .write()
+            .expect("Session add")
+            .insert(key, session);
     }
 
+    /// Removes the session and its group memberships
     pub fn remove(&self, key: Key) {
-        self.0.write().expect("Session remove").remove(&key);
+        let mut groups = self.groups.write().expect("Session remove");
+        self.sessions
+            .write()
+            .expect("Session remove")
+            .swap_remove(&key);
+        for group in groups.joined.swap_remove(&key).unwrap_or_default() {
+            groups.remove_member(&group, &key);
+        }
     }
 
     pub fn send_packet_to(&self, session_key: Key, pkt: ShroomPacket) -> anyhow::Result<()> {
-        self.0
+        self.sessions
             .read()
             .expect("Session send to")
             .get(&session_key)
@@ -40,7 +95,7 @@ impl<Key: Hash + Eq> SessionSet<Key> {
     }
 
     pub fn broadcast_packet(&self, pkt: ShroomPacket, src: Key) -> anyhow::Result<()> {
-        for (key, sess) in self.0.read().expect("Session broadcast").iter() {
+        for (key, sess) in self.sessions.read().expect("Session broadcast").iter() {
             if src == *key {
                 continue;
             }
@@ -49,28 +104,332 @@ impl<Key: Hash + Eq> SessionSet<Key> {
         Ok(())
     }
 
+    /// Encodes the packet once per codec of the sessions and sends It to every session except `src`
     pub fn broadcast_pkt<T: EncodePacket + HasOpcode>(
         &self,
         pkt: T,
         src: Key,
     ) -> anyhow::Result<()> {
-        let mut pw = PacketWriter::default();
-        pw.write_opcode(T::OPCODE)?;
-        pkt.encode_packet(&mut pw)?;
-
-        self.broadcast_packet(pw.into_packet(), src)?;
+        let mut pkts = CodecPackets::new(pkt);
+        for (key, sess) in self.sessions.read().expect("Session broadcast").iter() {
+            if src == *key {
+                continue;
+            }
+            if let Some(pkt) = pkts.get(sess.str_codec()) {
+                let _ = sess.try_send_bytes_with(Priority::Normal, pkt.as_ref().clone());
+            }
+        }
         Ok(())
     }
 
+    /// Encodes the packet with the codec of the session and sends It
     pub async fn send_pkt_to<T: EncodePacket + HasOpcode>(
         &self,
         session_key: Key,
         pkt: T,
     ) -> anyhow::Result<()> {
-        let mut pw = PacketWriter::default();
-        pw.write_opcode(T::OPCODE)?;
-        pkt.encode_packet(&mut pw)?;
+        self.sessions
+            .read()
+            .expect("Session send to")
+            .get(&session_key)
+            .ok_or_else(|| anyhow::format_err!("Unable to find session"))?
+            .try_send_encode_pkt(pkt)
+    }
+
+    /// Removes the session from the group, an empty group is removed
+    pub fn leave(&self, group: &Group, key: &Key) -> bool {
+        let mut groups = self.groups.write().expect("Group leave");
+        if let Some(joined) = groups.joined.get_mut(key) {
+            joined.shift_remove(group);
+            if joined.is_empty() {
+                groups.joined.swap_remove(key);
+            }
+        }
+        groups.remove_member(group, key)
+    }
+
+    /// Number of sessions in the group
+    pub fn group_len(&self, group: &Group) -> usize {
+        self.groups
+            .read()
+            .expect("Group len")
+            .members
+            .get(group)
+            .map_or(0, |members| members.len())
+    }
+
+    /// Sends the packet to every session of the group, for which the filter returns true.
+    /// The packet data is shared across all sessions and the number of sessions,
+    /// which received the packet is returned
+    pub fn broadcast_group_filter(
+        &self,
+        group: &Group,
+        prio: Priority,
+        pkt: &ShroomPacket,
+        filter: impl Fn(&Key) -> bool,
+    ) -> usize {
+        self.send_group_filter(group, filter, |sess| {
+            sess.try_send_bytes_with(prio, pkt.as_ref().clone()).is_ok()
+        })
+    }
+
+    /// Sends the packet to every session of the group except the excluded ones
+    pub fn broadcast_group_except(
+        &self,
+        group: &Group,
+        prio: Priority,
+        pkt: &ShroomPacket,
+        except: &HashSet<Key>,
+    ) -> usize {
+        self.broadcast_group_filter(group, prio, pkt, |key| !except.contains(key))
+    }
+
+    /// Encodes the packet once per codec of the members and sends It to every session
+    /// of the group except the excluded ones. Sessions, whose codec can't encode
+    /// the packet are skipped
+    pub fn broadcast_group_pkt<T: EncodePacket + HasOpcode>(
+        &self,
+        group: &Group,
+        prio: Priority,
+        pkt: T,
+        except: &HashSet<Key>,
+    ) -> anyhow::Result<usize> {
+        let mut pkts = CodecPackets::new(pkt);
+        Ok(self.send_group_filter(
+            group,
+            |key| !except.contains(key),
+            |sess| {
+                pkts.get(sess.str_codec())
+                    .is_some_and(|pkt| sess.try_send_bytes_with(prio, pkt.as_ref().clone()).is_ok())
+            },
+        ))
+    }
+
+    /// Calls `send` for every session of the group, for which the filter returns true.
+    /// Returns the number of sessions for which `send` succeeded
+    fn send_group_filter(
+        &self,
+        group: &Group,
+        filter: impl Fn(&Key) -> bool,
+        mut send: impl FnMut(&SharedSessionHandle) -> bool,
+    ) -> usize {
+        let groups = self.groups.read().expect("Group broadcast");
+        let Some(members) = groups.members.get(group) else {
+            return 0;
+        };
+
+        let sessions = self.sessions.read().expect("Group broadcast");
+        members
+            .iter()
+            .filter(|key| filter(key))
+            .filter_map(|key| sessions.get(key))
+            .filter(|sess| send(sess))
+            .count()
+    }
+}
+
+impl<Key: Hash + Eq + Clone, Group: Hash + Eq + Clone> SessionSet<Key, Group> {
+    /// Adds the session to the group, the group is created If It doesn't exist.
+    /// Returns false If the session is not in the set
+    pub fn join(&self, group: Group, key: Key) -> bool {
+        let mut groups = self.groups.write().expect("Group join");
+        if !self.sessions.read().expect("Group join").contains_key(&key) {
+            return false;
+        }
+
+        groups
+            .joined
+            .entry(key.clone())
+            .or_default()
+            .insert(group.clone());
+        groups.members.entry(group).or_default().insert(key);
+        true
+    }
+
+    /// Keys of the sessions in the group, in the order they joined
+    pub fn members(&self, group: &Group) -> Vec<Key> {
+        self.groups
+            .read()
+            .expect("Group members")
+            .members
+            .get(group)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Packet, which is encoded once for every codec of the receiving sessions
+struct CodecPackets<T> {
+    pkt: T,
+    encoded: Vec<(StringCodec, Option<ShroomPacket>)>,
+}
+
+impl<T: EncodePacket + HasOpcode> CodecPackets<T> {
+    fn new(pkt: T) -> Self {
+        Self {
+            pkt,
+            encoded: Vec::new(),
+        }
+    }
+
+    /// Gets the packet encoded with the codec, `None` If the codec can't encode the packet
+    fn get(&mut self, str_codec: StringCodec) -> Option<&ShroomPacket> {
+        // There are only a few codecs, so a linear search is enough
+        let ix = match self
+            .encoded
+            .iter()
+            .position(|(codec, _)| *codec == str_codec)
+        {
+            Some(ix) => ix,
+            None => {
+                let pkt = encode_pkt(&self.pkt, str_codec)
+                    .inspect_err(|err| log::debug!("Unable to encode broadcast: {err}"))
+                    .ok();
+                self.encoded.push((str_codec, pkt));
+                self.encoded.len() - 1
+            }
+        };
+        self.encoded[ix].1.as_ref()
+    }
+}
+
+fn encode_pkt<T: EncodePacket + HasOpcode>(
+    pkt: &T,
+    str_codec: StringCodec,
+) -> anyhow::Result<ShroomPacket> {
+    let mut pw = PacketWriter::with_str_codec(BytesMut::new(), str_codec);
+    pw.write_opcode(T::OPCODE)?;
+    pkt.encode_packet(&mut pw)?;
+    Ok(ShroomPacket::from_writer(pw))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use futures::StreamExt;
+
+    use crate::{
+        net::service::SharedSessionHandle, opcode::WithOpcode, packet::StringCodec,
+        util::priority_pipe::Priority, ShroomPacket,
+    };
+
+    use super::SessionSet;
+
+    #[tokio::test]
+    async fn group_broadcast() {
+        let set = SessionSet::<u32, &str>::new();
+        let mut rxs: Vec<_> = (0..4)
+            .map(|key| {
+                let (sess, rx) = SharedSessionHandle::new();
+                set.add(key, sess);
+                rx
+            })
+            .collect();
+
+        assert!(set.join("map", 0));
+        assert!(set.join("map", 1));
+        assert!(set.join("map", 2));
+        assert!(set.join("party", 3));
+        assert!(set.join("party", 2));
+        // Unknown sessions can't join
+        assert!(!set.join("map", 4));
+        assert_eq!(set.members(&"map"), [0, 1, 2]);
+
+        let sent = set
+            .broadcast_group_pkt(
+                &"map",
+                Priority::Low,
+                WithOpcode::<1, u8>(7),
+                &HashSet::from([0]),
+            )
+            .unwrap();
+        assert_eq!(sent, 2);
+        for rx in &mut rxs[1..3] {
+            assert_eq!(&rx.next().await.unwrap().unwrap()[..], [1, 0, 7]);
+        }
+
+        // Nothing was sent to the excluded or other sessions
+        let mut batch = Vec::new();
+        rxs[0].try_drain(1, &mut batch).unwrap();
+        rxs[3].try_drain(1, &mut batch).unwrap();
+        assert!(batch.is_empty());
+
+        assert!(set.leave(&"map", &1));
+        assert!(!set.leave(&"map", &1));
+        set.remove(2);
+        assert_eq!(set.members(&"map"), [0]);
+        assert_eq!(set.members(&"party"), [3]);
+
+        set.remove(3);
+        assert_eq!(set.group_len(&"party"), 0);
+        assert!(set.members(&"party").is_empty());
+
+        // A removed session can't join again
+        assert!(!set.join("party", 3));
+    }
+
+    #[tokio::test]
+    async fn group_broadcast_shared() {
+        let set = SessionSet::<u32, &str>::new();
+        let mut rxs: Vec<_> = (0..2)
+            .map(|key| {
+                let (sess, rx) = SharedSessionHandle::new();
+                set.add(key, sess);
+                assert!(set.join("map", key));
+                rx
+            })
+            .collect();
+
+        // Framed lanes pass the encoded packet without copying It
+        let pkt = ShroomPacket::from_data(vec![1, 0, 7].into());
+        assert_eq!(
+            set.broadcast_group_except(&"map", Priority::Normal, &pkt, &HashSet::new()),
+            2
+        );
+        for rx in &mut rxs {
+            let frame = rx.next().await.unwrap().unwrap();
+            assert_eq!(frame.as_ptr(), pkt.as_ref().as_ptr());
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    #[tokio::test]
+    async fn group_broadcast_codecs() {
+        let set = SessionSet::<u32, &str>::new();
+        let codecs = [
+            StringCodec::Cp949,
+            StringCodec::Cp949,
+            StringCodec::Utf8,
+            StringCodec::Cp1252,
+        ];
+        let mut rxs: Vec<_> = (0..4)
+            .map(|key| {
+                let (sess, rx) = SharedSessionHandle::new();
+                set.add(key, sess.with_str_codec(codecs[key as usize]));
+                assert!(set.join("map", key));
+                rx
+            })
+            .collect();
+
+        // The cp1252 session can't receive the hangul string
+        let pkt = WithOpcode::<2, String>("버섯".to_string());
+        let sent = set
+            .broadcast_group_pkt(&"map", Priority::Normal, pkt, &HashSet::new())
+            .unwrap();
+        assert_eq!(sent, 3);
+
+        // Sessions with the same codec share the encoded packet
+        let cp949 = rxs[0].next().await.unwrap().unwrap();
+        assert_eq!(&cp949[..], b"\x02\x00\x04\x00\xB9\xF6\xBC\xB8");
+        let frame = rxs[1].next().await.unwrap().unwrap();
+        assert_eq!(frame.as_ptr(), cp949.as_ptr());
+
+        let utf8 = rxs[2].next().await.unwrap().unwrap();
+        assert_eq!(&utf8[4..], "버섯".as_bytes());
 
-        self.send_packet_to(session_key, pw.into_packet())
+        let mut batch = Vec::new();
+        rxs[3].try_drain(1, &mut batch).unwrap();
+        assert!(batch.is_empty());
     }
 }
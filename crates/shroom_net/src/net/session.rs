@@ -1,4 +1,4 @@
-use std::{io, net::SocketAddr};
+use std::{future::Future, io, net::SocketAddr, time::Duration};
 
 use bytes::BytesMut;
 use futures::{Sink, SinkExt, Stream, StreamExt};
@@ -6,22 +6,81 @@ use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::{TcpStream, ToSocketAddrs},
 };
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Framed, FramedParts, FramedRead, FramedWrite};
 
 use crate::{
-    crypto::SharedCryptoContext, EncodePacket, HasOpcode, NetError, NetOpcode, NetResult,
-    PacketBuffer, PacketWriter, ShroomPacket,
+    crypto::SharedCryptoContext, packet::StringCodec, EncodePacket, HasOpcode, NetError,
+    NetOpcode, NetResult, PacketBuffer, PacketWriter, ShroomPacket,
 };
 
-use super::codec::{handshake::Handshake, packet_codec::PacketCodec};
+use super::{
+    codec::{
+        handshake::{Handshake, HandshakePolicy},
+        packet_codec::PacketCodec,
+    },
+    split::{ShroomSessionReader, ShroomSessionWriter},
+};
 
 /// Marker for traits which implement `AsyncWrite`, `AsyncRead` and `Unpin`
 pub trait SessionTransport: AsyncWrite + AsyncRead + Unpin {}
 impl<T> SessionTransport for T where T: AsyncWrite + AsyncRead + Unpin {}
 
+/// Transports which might know the address of the peer,
+/// transports without an address can opt out with an empty impl
+pub trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+// In-memory transport has no address
+impl PeerAddr for tokio::io::DuplexStream {}
+
+#[cfg(feature = "turmoil")]
+impl PeerAddr for turmoil::net::TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        turmoil::net::TcpStream::peer_addr(self).ok()
+    }
+}
+
+/// Encodes the opcode and the data onto the cleared buffer
+pub(crate) fn encode_packet_with_opcode(
+    buf: &mut BytesMut,
+    str_codec: StringCodec,
+    op: impl NetOpcode,
+    data: impl EncodePacket,
+) -> NetResult<()> {
+    buf.clear();
+    buf.reserve(4096);
+
+    let mut pw = PacketWriter::with_str_codec(buf, str_codec);
+    pw.write_opcode(op)?;
+    data.encode_packet(&mut pw)
+}
+
+/// Fails the write with `NetError::WriteStallTimeout`, If It doesn't complete within the timeout
+pub(crate) async fn with_write_timeout<F: Future<Output = NetResult<()>>>(
+    timeout: Option<Duration>,
+    fut: F,
+) -> NetResult<()> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| NetError::WriteStallTimeout)?,
+        None => fut.await,
+    }
+}
+
 pub struct ShroomSession<T> {
     codec: Framed<T, PacketCodec>,
     encode_buffer: BytesMut,
+    write_timeout: Option<Duration>,
 }
 
 impl<T> ShroomSession<T>
@@ -33,6 +92,7 @@ where
         Self {
             codec: Framed::new(io, codec),
             encode_buffer: BytesMut::new(),
+            write_timeout: None,
         }
     }
 
@@ -48,10 +108,21 @@ where
 
     /// Initialize a client session, by reading a handshake first
     pub async fn initialize_client_session(
+        io: T,
+        ctx: SharedCryptoContext,
+    ) -> NetResult<(Self, Handshake)> {
+        Self::initialize_client_session_with_policy(io, ctx, &HandshakePolicy::default()).await
+    }
+
+    /// Initialize a client session, by reading a handshake first
+    /// which has to pass the policy
+    pub async fn initialize_client_session_with_policy(
         mut io: T,
         ctx: SharedCryptoContext,
+        policy: &HandshakePolicy,
     ) -> NetResult<(Self, Handshake)> {
         let handshake = Handshake::read_handshake_async(&mut io).await?;
+        policy.check(&handshake)?;
         let sess = Self::from_client_handshake(io, ctx, handshake.clone());
 
         Ok((sess, handshake))
@@ -69,6 +140,18 @@ where
         Self::new(io, codec)
     }
 
+    /// Codec which is used for the strings of this session
+    pub fn str_codec(&self) -> StringCodec {
+        self.codec.codec().str_codec()
+    }
+
+    /// Sets the timeout for the send methods, a send which doesn't complete
+    /// within the timeout fails with `NetError::WriteStallTimeout`.
+    /// Writes via the `Sink` impl are not covered
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
     pub async fn read_packet(&mut self) -> NetResult<ShroomPacket> {
         match self.codec.next().await {
             Some(p) => Ok(p?),
@@ -77,18 +160,26 @@ where
     }
 
     pub async fn send_packet_buffer(&mut self, buf: &PacketBuffer) -> NetResult<()> {
-        // It is required to send the packets one-by-one, because the client doesn't support
-        // other ways
-        for pkt in buf.packets() {
-            self.send_packet(pkt).await?;
-        }
+        self.send_packets(buf.packets()).await
+    }
 
-        Ok(())
+    /// Sends all packets, every packet is encrypted into It's own frame
+    /// because the client doesn't support other ways, but all frames are written with one flush
+    pub async fn send_packets<B: AsRef<[u8]>>(
+        &mut self,
+        pkts: impl IntoIterator<Item = B>,
+    ) -> NetResult<()> {
+        // Encode directly onto the write buffer, previously buffered data is kept
+        let mut buf = std::mem::take(self.codec.write_buffer_mut());
+        let res = self.codec.codec_mut().encode_all(pkts, &mut buf);
+        *self.codec.write_buffer_mut() = buf;
+        res?;
+
+        with_write_timeout(self.write_timeout, self.codec.flush()).await
     }
 
     pub async fn send_packet(&mut self, data: &[u8]) -> NetResult<()> {
-        self.codec.send(data).await?;
-        Ok(())
+        with_write_timeout(self.write_timeout, self.codec.send(data)).await
     }
 
     pub async fn send_encode_packet<P: EncodePacket + HasOpcode>(
@@ -103,22 +194,65 @@ where
         op: impl NetOpcode,
         data: impl EncodePacket,
     ) -> NetResult<()> {
-        self.encode_buffer.clear();
-        self.encode_buffer.reserve(4096);
-
-        // Encode the packet onto the buffer
-        let mut pw = PacketWriter::new(&mut self.encode_buffer);
-        pw.write_opcode(op)?;
-        data.encode_packet(&mut pw)?;
-
-        self.codec.send(&self.encode_buffer).await?;
-        Ok(())
+        let str_codec = self.str_codec();
+        encode_packet_with_opcode(&mut self.encode_buffer, str_codec, op, data)?;
+        with_write_timeout(self.write_timeout, self.codec.send(&self.encode_buffer)).await
     }
 
     pub async fn close(mut self) -> NetResult<()> {
         self.codec.close().await?;
         Ok(())
     }
+
+    /// Splits the session into a read and a write half,
+    /// which use their own decode and encode codec
+    pub fn split(self) -> (ShroomSessionReader<T>, ShroomSessionWriter<T>) {
+        let parts = self.codec.into_parts();
+        let PacketCodec { decode, encode } = parts.codec;
+        let str_codec = decode.str_codec;
+        let (r, w) = tokio::io::split(parts.io);
+
+        // Keep the already buffered data
+        let mut framed_read = FramedRead::new(r, decode);
+        *framed_read.read_buffer_mut() = parts.read_buf;
+        let mut framed_write = FramedWrite::new(w, encode);
+        *framed_write.write_buffer_mut() = parts.write_buf;
+
+        (
+            ShroomSessionReader {
+                framed: framed_read,
+            },
+            ShroomSessionWriter {
+                framed: framed_write,
+                encode_buffer: self.encode_buffer,
+                str_codec,
+                write_timeout: self.write_timeout,
+            },
+        )
+    }
+
+    /// Joins the halves of `split` back together
+    /// Panics If the halves are not from the same session
+    pub fn unsplit(reader: ShroomSessionReader<T>, writer: ShroomSessionWriter<T>) -> Self {
+        let read = reader.framed.into_parts();
+        let write = writer.framed.into_parts();
+
+        let mut parts = FramedParts::new::<&[u8]>(
+            read.io.unsplit(write.io),
+            PacketCodec {
+                decode: read.codec,
+                encode: write.codec,
+            },
+        );
+        parts.read_buf = read.read_buf;
+        parts.write_buf = write.write_buf;
+
+        Self {
+            codec: Framed::from_parts(parts),
+            encode_buffer: writer.encode_buffer,
+            write_timeout: writer.write_timeout,
+        }
+    }
 }
 
 impl<T: SessionTransport> Stream for ShroomSession<T> {
@@ -139,7 +273,7 @@ impl<B: AsRef<[u8]>, T: SessionTransport> Sink<B> for ShroomSession<T> {
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.get_mut().codec.poll_close_unpin(cx)
+        Sink::<&[u8]>::poll_ready(std::pin::Pin::new(&mut self.get_mut().codec), cx)
     }
 
     fn start_send(self: std::pin::Pin<&mut Self>, item: B) -> Result<(), Self::Error> {
@@ -176,9 +310,19 @@ impl ShroomSession<TcpStream> {
     pub async fn connect(
         addr: impl ToSocketAddrs,
         ctx: SharedCryptoContext,
+    ) -> NetResult<(Self, Handshake)> {
+        Self::connect_with_policy(addr, ctx, &HandshakePolicy::default()).await
+    }
+
+    /// Connect to the given addr with the crypto context,
+    /// the handshake has to pass the policy
+    pub async fn connect_with_policy(
+        addr: impl ToSocketAddrs,
+        ctx: SharedCryptoContext,
+        policy: &HandshakePolicy,
     ) -> NetResult<(Self, Handshake)> {
         let socket = TcpStream::connect(addr).await?;
-        Self::initialize_client_session(socket, ctx).await
+        Self::initialize_client_session_with_policy(socket, ctx, policy).await
     }
 }
 
@@ -190,10 +334,11 @@ mod tests {
     use crate::{
         crypto::SharedCryptoContext,
         net::{
-            codec::handshake::{LocaleCode, HandshakeVersion},
+            codec::handshake::{HandshakePolicy, HandshakeVersion, LocaleCode},
             service::{BasicHandshakeGenerator, HandshakeGenerator},
             ShroomSession,
         },
+        NetError,
     };
 
     const PORT: u16 = 1738;
@@ -202,14 +347,11 @@ mod tests {
         TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await
     }
 
-    #[test]
-    fn echo() -> anyhow::Result<()> {
-        const ECHO_DATA: [&'static [u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
-        const LOCALE: LocaleCode = LocaleCode::Global;
-
-        let mut sim = turmoil::Builder::new().build();
-        const V: HandshakeVersion = HandshakeVersion::v83();
+    const ECHO_DATA: [&[u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
+    const LOCALE: LocaleCode = LocaleCode::Global;
+    const V: HandshakeVersion = HandshakeVersion::v83();
 
+    fn echo_server(sim: &mut turmoil::Sim) {
         sim.host("server", || async move {
             let crypto_ctx = SharedCryptoContext::default();
             let hshake_gen = BasicHandshakeGenerator::new(V, LOCALE);
@@ -231,6 +373,12 @@ mod tests {
                 }
             }
         });
+    }
+
+    #[test]
+    fn echo() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        echo_server(&mut sim);
 
         sim.client("client", async move {
             let socket = TcpStream::connect(("server", PORT)).await?;
@@ -252,4 +400,75 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn handshake_policy() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        echo_server(&mut sim);
+
+        sim.client("client", async move {
+            let policy = HandshakePolicy::default()
+                .with_version(V)
+                .with_locales([LOCALE]);
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let (mut sess, handshake) = ShroomSession::initialize_client_session_with_policy(
+                socket,
+                SharedCryptoContext::default(),
+                &policy,
+            )
+            .await?;
+            assert_eq!(handshake.locale, LOCALE);
+            sess.send_packet(&[1, 2, 3]).await?;
+            assert_eq!(sess.read_packet().await?.as_ref(), &[1u8, 2, 3][..]);
+            // The echo server only handles one session at a time
+            drop(sess);
+
+            let policy = HandshakePolicy::default().with_locales([LocaleCode::Korea]);
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let res = ShroomSession::initialize_client_session_with_policy(
+                socket,
+                SharedCryptoContext::default(),
+                &policy,
+            )
+            .await;
+            assert!(matches!(res, Err(NetError::HandshakeMismatch { .. })));
+
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_echo() -> anyhow::Result<()> {
+        let mut sim = turmoil::Builder::new().build();
+        echo_server(&mut sim);
+
+        sim.client("client", async move {
+            let socket = TcpStream::connect(("server", PORT)).await?;
+            let (sess, _) =
+                ShroomSession::initialize_client_session(socket, SharedCryptoContext::default())
+                    .await?;
+
+            // Write all packets with one flush before reading the echo
+            let (mut r, mut w) = sess.split();
+            w.send_packets(ECHO_DATA).await?;
+            for data in ECHO_DATA.iter() {
+                let pkt = r.read_packet().await?;
+                assert_eq!(pkt.as_ref(), *data);
+            }
+
+            let mut sess = ShroomSession::unsplit(r, w);
+            sess.send_packet(&[1, 2, 3]).await?;
+            assert_eq!(sess.read_packet().await?.as_ref(), &[1u8, 2, 3][..]);
+
+            Ok(())
+        });
+
+        sim.run().unwrap();
+
+        Ok(())
+    }
 }
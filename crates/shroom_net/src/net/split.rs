@@ -0,0 +1,131 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    packet::StringCodec, EncodePacket, HasOpcode, NetError, NetOpcode, NetResult, PacketBuffer,
+    ShroomPacket,
+};
+
+use super::{
+    codec::packet_codec::{PacketDecodeCodec, PacketEncodeCodec},
+    session::{encode_packet_with_opcode, with_write_timeout},
+    SessionTransport,
+};
+
+/// Read half of a `ShroomSession`, created by `ShroomSession::split`
+pub struct ShroomSessionReader<T> {
+    pub(crate) framed: FramedRead<ReadHalf<T>, PacketDecodeCodec>,
+}
+
+impl<T: SessionTransport> ShroomSessionReader<T> {
+    /// Codec which is used for the strings of this session
+    pub fn str_codec(&self) -> StringCodec {
+        self.framed.decoder().str_codec
+    }
+
+    pub async fn read_packet(&mut self) -> NetResult<ShroomPacket> {
+        match self.framed.next().await {
+            Some(p) => Ok(p?),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
+
+impl<T: SessionTransport> Stream for ShroomSessionReader<T> {
+    type Item = NetResult<ShroomPacket>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().framed.poll_next_unpin(cx)
+    }
+}
+
+/// Write half of a `ShroomSession`, created by `ShroomSession::split`
+pub struct ShroomSessionWriter<T> {
+    pub(crate) framed: FramedWrite<WriteHalf<T>, PacketEncodeCodec>,
+    pub(crate) encode_buffer: BytesMut,
+    pub(crate) str_codec: StringCodec,
+    pub(crate) write_timeout: Option<Duration>,
+}
+
+impl<T: SessionTransport> ShroomSessionWriter<T> {
+    /// Codec which is used for the strings of this session
+    pub fn str_codec(&self) -> StringCodec {
+        self.str_codec
+    }
+
+    /// Sets the timeout for the send methods, see `ShroomSession::set_write_timeout`
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    pub async fn send_packet_buffer(&mut self, buf: &PacketBuffer) -> NetResult<()> {
+        self.send_packets(buf.packets()).await
+    }
+
+    /// Sends all packets with one flush, see `ShroomSession::send_packets`
+    pub async fn send_packets<B: AsRef<[u8]>>(
+        &mut self,
+        pkts: impl IntoIterator<Item = B>,
+    ) -> NetResult<()> {
+        let mut buf = std::mem::take(self.framed.write_buffer_mut());
+        let res = self.framed.encoder_mut().encode_all(pkts, &mut buf);
+        *self.framed.write_buffer_mut() = buf;
+        res?;
+
+        with_write_timeout(self.write_timeout, self.framed.flush()).await
+    }
+
+    pub async fn send_packet(&mut self, data: &[u8]) -> NetResult<()> {
+        with_write_timeout(self.write_timeout, self.framed.send(data)).await
+    }
+
+    pub async fn send_encode_packet<P: EncodePacket + HasOpcode>(
+        &mut self,
+        data: P,
+    ) -> NetResult<()> {
+        self.send_encode_packet_with_opcode(P::OPCODE, data).await
+    }
+
+    pub async fn send_encode_packet_with_opcode(
+        &mut self,
+        op: impl NetOpcode,
+        data: impl EncodePacket,
+    ) -> NetResult<()> {
+        encode_packet_with_opcode(&mut self.encode_buffer, self.str_codec, op, data)?;
+        with_write_timeout(self.write_timeout, self.framed.send(&self.encode_buffer)).await
+    }
+
+    pub async fn close(mut self) -> NetResult<()> {
+        self.framed.close().await?;
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>, T: SessionTransport> Sink<B> for ShroomSessionWriter<T> {
+    type Error = NetError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&[u8]>::poll_ready(Pin::new(&mut self.get_mut().framed), cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: B) -> Result<(), Self::Error> {
+        self.get_mut().framed.start_send_unpin(item.as_ref())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&[u8]>::poll_flush(Pin::new(&mut self.get_mut().framed), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<&[u8]>::poll_close(Pin::new(&mut self.get_mut().framed), cx)
+    }
+}
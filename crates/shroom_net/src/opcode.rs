@@ -11,6 +11,18 @@ pub trait NetOpcode: TryFrom<u16> + Into<u16> + Copy + Clone + Send + Sync {
 /// Blanket implementation for u16
 impl NetOpcode for u16 {}
 
+/// Phase of a session, which decides the allowed opcodes, see `PhaseGuard`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SessionPhase {
+    #[default]
+    Handshake,
+    LoggedOut,
+    LoggedIn,
+    InGame,
+    /// Application specific phase
+    Custom(u8),
+}
+
 /// Adds an opcode to the type by implementing this trait
 pub trait HasOpcode {
     /// Opcode type
@@ -39,6 +51,10 @@ where
         self.0.packet_len()
     }
 
+    fn packet_len_with(&self, str_codec: crate::packet::StringCodec) -> NetResult<usize> {
+        self.0.packet_len_with(str_codec)
+    }
+
     fn encode_packet<B: bytes::BufMut>(&self, pw: &mut crate::PacketWriter<B>) -> NetResult<()> {
         self.0.encode_packet(pw)
     }
@@ -1,6 +1,7 @@
 pub mod packet_data_context;
 pub mod proto;
 pub mod reader;
+pub mod str_codec;
 pub mod writer;
 
 use crate::NetResult;
@@ -8,6 +9,7 @@ use bytes::{Bytes, BytesMut};
 
 /// Export the reader and writer here
 pub use reader::PacketReader;
+pub use str_codec::{StrArena, StringCodec};
 pub use writer::PacketWriter;
 
 // Re-export proto
@@ -29,26 +31,52 @@ pub(crate) fn shroom128_to_bytes(v: u128) -> [u8; 16] {
     bytemuck::cast(blocks)
 }
 
-/// Required length to encode this string
+/// Required length to encode this string as UTF-8
 pub(crate) fn packet_str_len(s: &str) -> usize {
     // len(u16) + data
     2 + s.len()
 }
 
+/// Required length to encode this string with the codec
+pub(crate) fn packet_str_len_with(s: &str, str_codec: StringCodec) -> NetResult<usize> {
+    Ok(2 + str_codec.encoded_len(s)?)
+}
+
 #[derive(Clone, Default, Debug)]
-pub struct ShroomPacket(Bytes);
+pub struct ShroomPacket {
+    data: Bytes,
+    str_codec: StringCodec,
+    /// Converted strings, which are borrowed from the packet
+    str_arena: StrArena,
+}
 
 impl ShroomPacket {
     pub fn from_data(data: Bytes) -> Self {
-        Self(data)
+        Self {
+            data,
+            str_codec: StringCodec::default(),
+            str_arena: StrArena::default(),
+        }
     }
 
     pub fn from_writer(pw: PacketWriter<BytesMut>) -> Self {
-        Self::from_data(pw.buf.freeze())
+        let str_codec = pw.str_codec();
+        Self::from_data(pw.buf.freeze()).with_str_codec(str_codec)
+    }
+
+    /// Sets the codec which is used to decode the strings of this packet
+    pub fn with_str_codec(mut self, str_codec: StringCodec) -> Self {
+        self.str_codec = str_codec;
+        self
+    }
+
+    /// Gets the codec which is used to decode the strings of this packet
+    pub fn str_codec(&self) -> StringCodec {
+        self.str_codec
     }
 
     pub fn into_reader(&self) -> PacketReader<'_> {
-        PacketReader::new(&self.0)
+        PacketReader::with_str_codec(&self.data, self.str_codec).with_str_arena(&self.str_arena)
     }
 
     pub fn read_opcode(&self) -> NetResult<u16> {
@@ -58,7 +86,7 @@ impl ShroomPacket {
 
 impl AsRef<Bytes> for ShroomPacket {
     fn as_ref(&self) -> &Bytes {
-        &self.0
+        &self.data
     }
 }
 
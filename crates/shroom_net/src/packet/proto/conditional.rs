@@ -61,8 +61,11 @@ where
     }
 
     fn packet_len_cond(&self, cond: bool) -> usize {
-        cond.then(|| self.as_ref().expect("Must have value").packet_len())
-            .unwrap_or(0)
+        if cond {
+            self.as_ref().expect("Must have value").packet_len()
+        } else {
+            0
+        }
     }
 }
 
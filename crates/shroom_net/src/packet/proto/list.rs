@@ -6,7 +6,7 @@ use std::marker::PhantomData;
 use bytes::BufMut;
 use derive_more::{Deref, DerefMut, From, Into};
 
-use crate::{NetResult, PacketReader, PacketWriter, SizeHint};
+use crate::{packet::StringCodec, NetResult, PacketReader, PacketWriter, SizeHint};
 
 use super::{DecodePacket, DecodePacketOwned, EncodePacket};
 
@@ -213,6 +213,16 @@ where
         L::SIZE_HINT.0.expect("Index size")
             + self.items.iter().map(|v| v.packet_len()).sum::<usize>()
     }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        Ok(L::SIZE_HINT.0.expect("Index size")
+            + self
+                .items
+                .iter()
+                .map(|v| v.packet_len_with(str_codec))
+                .sum::<NetResult<usize>>()?)
+    }
 }
 
 /// ShroomList with `u8` as length
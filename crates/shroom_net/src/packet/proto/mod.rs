@@ -28,7 +28,7 @@ pub use padding::Padding;
 pub use time::{ShroomDurationMs16, ShroomDurationMs32, ShroomExpirationTime, ShroomTime};
 pub use wrapped::{PacketTryWrapped, PacketWrapped};
 
-use crate::{NetResult, PacketReader, PacketWriter, ShroomPacket, SizeHint};
+use crate::{packet::StringCodec, NetResult, PacketReader, PacketWriter, ShroomPacket, SizeHint};
 
 /// Decodes this type from a packet reader
 pub trait DecodePacket<'de>: Sized {
@@ -79,9 +79,15 @@ pub trait EncodePacket: Sized {
     /// Size Hint for types with a known type at compile time
     const SIZE_HINT: SizeHint;
 
-    /// Get the encoded length of this type
+    /// Get the encoded length of this type, with UTF-8 strings
     fn packet_len(&self) -> usize;
 
+    /// Get the encoded length of this type, with the strings encoded by the codec.
+    /// Fails If a string can't be encoded by the codec
+    fn packet_len_with(&self, _str_codec: StringCodec) -> NetResult<usize> {
+        Ok(self.packet_len())
+    }
+
     /// Encodes the packet onto the writer
     fn encode_packet<T: BufMut>(&self, pw: &mut PacketWriter<T>) -> NetResult<()>;
 
@@ -148,15 +154,20 @@ macro_rules! impl_packet {
 
                     $($name.packet_len() +)*0
                 }
+
+                fn packet_len_with(&self, str_codec: $crate::packet::StringCodec) -> NetResult<usize> {
+                    #[allow(non_snake_case)]
+                    let ($($name,)*) = self;
+
+                    Ok($($name.packet_len_with(str_codec)? +)*0)
+                }
             }
 
 
             impl<'de, $($name,)*> $crate::DecodePacket<'de> for ($($name,)*)
             where $($name: $crate::DecodePacket<'de>,)* {
                 fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
-                    Ok((
-                        ($($name::decode_packet(pr)?,)*)
-                    ))
+                    Ok(($($name::decode_packet(pr)?,)*))
                 }
             }
     }
@@ -188,6 +188,7 @@ mod tests {
     }, test_util::test_encode_decode_owned};
 
     #[test]
+    #[allow(dead_code)]
     fn test_simple() {
         partial_data!(
             TestStats,
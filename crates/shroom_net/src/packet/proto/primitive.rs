@@ -2,7 +2,7 @@ use array_init::try_array_init;
 use bytes::BufMut;
 use either::Either;
 
-use crate::{NetResult, PacketReader, PacketWriter, SizeHint};
+use crate::{packet::StringCodec, NetResult, PacketReader, PacketWriter, SizeHint};
 
 use super::{DecodePacket, EncodePacket};
 
@@ -48,6 +48,14 @@ where
             Either::Right(r) => r.packet_len(),
         }
     }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        match self {
+            Either::Left(l) => l.packet_len_with(str_codec),
+            Either::Right(r) => r.packet_len_with(str_codec),
+        }
+    }
 }
 
 /// An optional tail, only read If there's enough data at the end available
@@ -151,6 +159,10 @@ impl<const N: usize, T: EncodePacket> EncodePacket for [T; N] {
     fn packet_len(&self) -> usize {
         self.iter().map(|v| v.packet_len()).sum()
     }
+
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        self.iter().map(|v| v.packet_len_with(str_codec)).sum()
+    }
 }
 
 impl<D: EncodePacket> EncodePacket for Vec<D> {
@@ -168,6 +180,10 @@ impl<D: EncodePacket> EncodePacket for Vec<D> {
     fn packet_len(&self) -> usize {
         self.iter().map(|v| v.packet_len()).sum()
     }
+
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        self.iter().map(|v| v.packet_len_with(str_codec)).sum()
+    }
 }
 
 impl<D: EncodePacket> EncodePacket for Option<D> {
@@ -185,6 +201,11 @@ impl<D: EncodePacket> EncodePacket for Option<D> {
     fn packet_len(&self) -> usize {
         self.as_ref().map(|v| v.packet_len()).unwrap_or(0)
     }
+
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        self.as_ref()
+            .map_or(Ok(0), |v| v.packet_len_with(str_codec))
+    }
 }
 
 #[cfg(test)]
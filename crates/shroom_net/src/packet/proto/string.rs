@@ -1,15 +1,13 @@
-use std::str::Utf8Error;
+use std::borrow::Cow;
 
 use arrayvec::{ArrayString, CapacityError};
 use bytes::BufMut;
 
 use crate::{
-    packet::packet_str_len, DecodePacket, EncodePacket, NetResult, PacketReader, PacketWriter,
-    SizeHint,
+    packet::{packet_str_len, packet_str_len_with, StringCodec},
+    DecodePacket, EncodePacket, NetError, NetResult, PacketReader, PacketWriter, SizeHint,
 };
 
-use super::PacketTryWrapped;
-
 // Basic support for String and str
 
 impl EncodePacket for String {
@@ -24,12 +22,17 @@ impl EncodePacket for String {
     fn packet_len(&self) -> usize {
         self.as_str().packet_len()
     }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        packet_str_len_with(self, str_codec)
+    }
 }
 
 impl<'de> DecodePacket<'de> for String {
     #[inline]
     fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
-        Ok(<&'de str>::decode_packet(pr)?.to_string())
+        Ok(pr.read_string_cow()?.into_owned())
     }
 }
 
@@ -40,7 +43,34 @@ impl<'de> DecodePacket<'de> for &'de str {
     }
 }
 
-impl<'a> EncodePacket for &'a str {
+impl EncodePacket for &str {
+    #[inline]
+    fn encode_packet<B: BufMut>(&self, pw: &mut PacketWriter<B>) -> NetResult<()> {
+        pw.write_str(self)
+    }
+
+    const SIZE_HINT: SizeHint = SizeHint::NONE;
+
+    #[inline]
+    fn packet_len(&self) -> usize {
+        packet_str_len(self)
+    }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        packet_str_len_with(self, str_codec)
+    }
+}
+
+// Cow only allocates If the string codec has to convert the string
+impl<'de> DecodePacket<'de> for Cow<'de, str> {
+    #[inline]
+    fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
+        pr.read_string_cow()
+    }
+}
+
+impl<'a> EncodePacket for Cow<'a, str> {
     #[inline]
     fn encode_packet<B: BufMut>(&self, pw: &mut PacketWriter<B>) -> NetResult<()> {
         pw.write_str(self)
@@ -52,6 +82,11 @@ impl<'a> EncodePacket for &'a str {
     fn packet_len(&self) -> usize {
         packet_str_len(self)
     }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        packet_str_len_with(self, str_codec)
+    }
 }
 
 // Basic support for ArrayString
@@ -70,23 +105,28 @@ impl<const N: usize> EncodePacket for arrayvec::ArrayString<N> {
     fn packet_len(&self) -> usize {
         packet_str_len(self.as_str())
     }
+
+    #[inline]
+    fn packet_len_with(&self, str_codec: StringCodec) -> NetResult<usize> {
+        packet_str_len_with(self.as_str(), str_codec)
+    }
 }
 
 impl<'de, const N: usize> DecodePacket<'de> for arrayvec::ArrayString<N> {
     #[inline]
     fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
-        let s = pr.read_string_limited(N)?;
-        Ok(arrayvec::ArrayString::from(s).unwrap())
+        let s = pr.read_string_limited_cow(N)?;
+        // A converted string might require more than N bytes as UTF-8
+        arrayvec::ArrayString::from(&s).map_err(|_| NetError::StringLimit(N))
     }
 }
 
 // Helper function which truncates after the first zero(included)
-fn from_c_str<const N: usize>(b: &[u8; N]) -> Result<ArrayString<N>, Utf8Error> {
-    let mut result = ArrayString::from_byte_string(b)?;
-    if let Some(i) = &result.find('\0') {
-        result.truncate(*i);
+fn truncate_c_str(b: &[u8]) -> &[u8] {
+    match b.iter().position(|&c| c == 0) {
+        Some(i) => &b[..i],
+        None => b,
     }
-    Ok(result)
 }
 
 /// A fixed string with the capacity of `N` bytes
@@ -95,20 +135,34 @@ fn from_c_str<const N: usize>(b: &[u8; N]) -> Result<ArrayString<N>, Utf8Error>
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq)]
 pub struct FixedPacketString<const N: usize>(pub arrayvec::ArrayString<N>);
 
-impl<const N: usize> PacketTryWrapped for FixedPacketString<N> {
-    type Inner = [u8; N];
+impl<const N: usize> EncodePacket for FixedPacketString<N> {
+    fn encode_packet<T: BufMut>(&self, pw: &mut PacketWriter<T>) -> NetResult<()> {
+        let data = pw.str_codec().encode(self.0.as_str())?;
+        if data.len() > N {
+            return Err(NetError::StringLimit(N));
+        }
 
-    fn packet_into_inner(&self) -> Self::Inner {
         // Write the data padded by zero up to N
         let mut buf = [0; N];
-        let b = &self.0;
-        buf[..b.len()].copy_from_slice(b.as_bytes());
-        buf
+        buf[..data.len()].copy_from_slice(&data);
+        pw.write_array(&buf)
+    }
+
+    const SIZE_HINT: SizeHint = SizeHint::new(N);
+
+    fn packet_len(&self) -> usize {
+        N
     }
+}
 
-    fn packet_try_from(v: Self::Inner) -> NetResult<Self> {
+impl<'de, const N: usize> DecodePacket<'de> for FixedPacketString<N> {
+    fn decode_packet(pr: &mut PacketReader<'de>) -> NetResult<Self> {
+        let data = pr.read_bytes(N)?;
+        let s = pr.str_codec().decode(truncate_c_str(data))?;
         // Convert the array to the string
-        Ok(Self(from_c_str(&v)?))
+        ArrayString::from(&s)
+            .map(Self)
+            .map_err(|_| NetError::StringLimit(N))
     }
 }
 
@@ -124,7 +178,10 @@ impl<'a, const N: usize> TryFrom<&'a str> for FixedPacketString<N> {
 mod tests {
     use arrayvec::ArrayString;
 
-    use crate::{packet::test_util::test_encode_decode_owned_all, test_encode_decode};
+    use crate::{
+        packet::{test_util::test_encode_decode_owned_all, StringCodec},
+        test_encode_decode, DecodePacket, EncodePacket, PacketReader, PacketWriter, ShroomPacket,
+    };
 
     use super::FixedPacketString;
 
@@ -160,4 +217,52 @@ mod tests {
             "a".try_into().unwrap(),
         ]);
     }
+
+    #[test]
+    fn cp1252_string() {
+        let mut pw = PacketWriter::with_str_codec(Vec::new(), StringCodec::Cp1252);
+        "Müller".encode_packet(&mut pw).unwrap();
+        FixedPacketString::<8>::try_from("Müller")
+            .unwrap()
+            .encode_packet(&mut pw)
+            .unwrap();
+
+        let data = pw.into_inner();
+        assert_eq!(&data[..8], b"\x06\x00M\xFCller");
+
+        let mut pr = PacketReader::with_str_codec(&data, StringCodec::Cp1252);
+        assert_eq!(String::decode_packet(&mut pr).unwrap(), "Müller");
+        assert_eq!(
+            FixedPacketString::<8>::decode_packet(&mut pr)
+                .unwrap()
+                .0
+                .as_str(),
+            "Müller"
+        );
+
+        // Non-ASCII cp1252 strings can only be borrowed from the arena of a packet
+        let mut pr = PacketReader::with_str_codec(&data, StringCodec::Cp1252);
+        assert!(<&str>::decode_packet(&mut pr).is_err());
+
+        let pkt = ShroomPacket::from_data(data.into()).with_str_codec(StringCodec::Cp1252);
+        let mut pr = pkt.into_reader();
+        let s = <&str>::decode_packet(&mut pr).unwrap();
+        assert_eq!(pkt.into_reader().read_string().unwrap(), "Müller");
+        assert_eq!(s, "Müller");
+    }
+
+    #[test]
+    fn str_len() {
+        let s = "Müller";
+        assert_eq!(s.packet_len(), 9);
+        assert_eq!(s.packet_len_with(StringCodec::Cp1252).unwrap(), 8);
+        assert_eq!(
+            (s, s.to_string())
+                .packet_len_with(StringCodec::Cp1252)
+                .unwrap(),
+            16
+        );
+        // Unencodable strings fail like the encoding does
+        assert!("버섯".packet_len_with(StringCodec::Cp1252).is_err());
+    }
 }
@@ -215,7 +215,7 @@ mod tests {
     fn dur() {
         test_encode_decode_owned_all([
             DurationMs::<u32>(1),
-            Duration::from_millis(100 as u64).into(),
+            Duration::from_millis(100).into(),
         ]);
     }
 
@@ -1,25 +1,45 @@
-use std::io::Cursor;
+use std::{borrow::Cow, io::Cursor};
 
 use bytes::Buf;
 
 use crate::{error::NetError, opcode::NetOpcode, NetResult};
 
-use super::shroom128_from_bytes;
+use super::{shroom128_from_bytes, StrArena, StringCodec};
 
 /// Packet Reader for reading data
 #[derive(Debug)]
 pub struct PacketReader<'a> {
     inner: Cursor<&'a [u8]>,
+    str_codec: StringCodec,
+    str_arena: Option<&'a StrArena>,
 }
 
 impl<'a> PacketReader<'a> {
     /// Create a new Pacekt reader from a slice
     pub fn new(inner: &'a [u8]) -> Self {
+        Self::with_str_codec(inner, StringCodec::default())
+    }
+
+    /// Create a new Packet reader from a slice, which decodes strings with the given codec
+    pub fn with_str_codec(inner: &'a [u8], str_codec: StringCodec) -> Self {
         Self {
             inner: Cursor::new(inner),
+            str_codec,
+            str_arena: None,
         }
     }
 
+    /// Keeps the converted strings in the arena, so they can be borrowed by `read_string`
+    pub fn with_str_arena(mut self, str_arena: &'a StrArena) -> Self {
+        self.str_arena = Some(str_arena);
+        self
+    }
+
+    /// Gets the codec which is used to decode strings
+    pub fn str_codec(&self) -> StringCodec {
+        self.str_codec
+    }
+
     /// Consume the reader as slice
     pub fn into_inner(self) -> &'a [u8] {
         self.inner.into_inner()
@@ -75,7 +95,11 @@ impl<'a> PacketReader<'a> {
 
     /// Create a sub reader based on this slice
     pub fn sub_reader(&self) -> Self {
-        Self::new(self.remaining_slice())
+        Self {
+            inner: Cursor::new(self.remaining_slice()),
+            str_codec: self.str_codec,
+            str_arena: self.str_arena,
+        }
     }
 
     /// Commit a sub reader
@@ -153,21 +177,47 @@ impl<'a> PacketReader<'a> {
         Ok(self.inner.get_f64_le())
     }
 
+    /// Read a string, a converted string is kept in the arena of the reader,
+    /// fails If the string had to be converted and the reader has no arena
     pub fn read_string(&mut self) -> NetResult<&'a str> {
+        let s = self.read_string_cow()?;
+        self.borrow_str(s)
+    }
+
+    /// Read string but limit the max length in bytes,
+    /// fails If the string had to be converted and the reader has no arena
+    pub fn read_string_limited(&mut self, limit: usize) -> NetResult<&'a str> {
+        let s = self.read_string_limited_cow(limit)?;
+        self.borrow_str(s)
+    }
+
+    /// Read a string, which is only allocated If the string codec requires a conversion
+    pub fn read_string_cow(&mut self) -> NetResult<Cow<'a, str>> {
         let n = self.read_u16()? as usize;
         let str_inner = self.read_bytes_inner::<&'a str>(n)?;
-        Ok(std::str::from_utf8(str_inner)?)
+        self.str_codec.decode(str_inner)
     }
 
-    /// Read string but limit the max length in bytes
-    pub fn read_string_limited(&mut self, limit: usize) -> NetResult<&'a str> {
+    /// Read string but limit the max length in bytes,
+    /// the string is only allocated If the string codec requires a conversion
+    pub fn read_string_limited_cow(&mut self, limit: usize) -> NetResult<Cow<'a, str>> {
         let n = self.read_u16()? as usize;
         if n > limit {
             return Err(NetError::StringLimit(limit));
         }
 
         let str_inner = self.read_bytes_inner::<&'a str>(n)?;
-        Ok(std::str::from_utf8(str_inner)?)
+        self.str_codec.decode(str_inner)
+    }
+
+    fn borrow_str(&self, s: Cow<'a, str>) -> NetResult<&'a str> {
+        match s {
+            Cow::Borrowed(s) => Ok(s),
+            Cow::Owned(s) => match self.str_arena {
+                Some(arena) => Ok(arena.alloc(s)),
+                None => Err(NetError::StringBorrow(self.str_codec)),
+            },
+        }
     }
 
     pub fn read_bytes(&mut self, n: usize) -> NetResult<&'a [u8]> {
@@ -179,7 +229,6 @@ impl<'a> PacketReader<'a> {
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     #[test]
@@ -191,4 +240,4 @@ mod tests {
         assert_eq!(r.remaining(), 9);
         assert_eq!(r.remaining_slice(), &b[1..]);
     }
-}
\ No newline at end of file
+}
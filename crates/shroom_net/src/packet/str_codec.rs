@@ -0,0 +1,291 @@
+use std::{borrow::Cow, sync::OnceLock};
+
+use crate::{net::codec::handshake::LocaleCode, NetError, NetResult};
+
+/// Code page which is used to en/decode the strings of a packet
+/// The original clients don't use UTF-8 but a legacy code page based on their locale,
+/// the legacy CJK code pages require the `encoding` feature, which is enabled by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StringCodec {
+    /// UTF-8, used for packets which are not bound to a session
+    #[default]
+    Utf8,
+    /// Windows-1252, the Latin-1 superset used by the GMS and EMS clients
+    Cp1252,
+    /// CP949 (Unified Hangul Code)
+    Cp949,
+    /// Shift-JIS
+    ShiftJis,
+    /// GBK
+    Gbk,
+    /// Big5
+    Big5,
+}
+
+/// Characters of the bytes 0x80..=0x9F in Windows-1252, the other bytes map to the same code point.
+/// The undefined bytes are mapped to the C1 controls like the WHATWG decoder does
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+fn cp1252_decode(b: u8) -> char {
+    match b {
+        0x80..=0x9F => CP1252_HIGH[usize::from(b - 0x80)],
+        _ => char::from(b),
+    }
+}
+
+fn cp1252_encode(c: char) -> Option<u8> {
+    match u8::try_from(c) {
+        Ok(b) if !(0x80..=0x9F).contains(&b) => Some(b),
+        _ => CP1252_HIGH
+            .iter()
+            .position(|&high| high == c)
+            .map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Node of the `StrArena`, the next node is set once
+struct StrNode {
+    s: Box<str>,
+    next: OnceLock<Box<StrNode>>,
+}
+
+/// Arena for the strings, which had to be converted by the codec,
+/// so they can be borrowed as long as the packet lives
+#[derive(Default)]
+pub struct StrArena {
+    head: OnceLock<Box<StrNode>>,
+}
+
+impl StrArena {
+    pub fn alloc(&self, s: String) -> &str {
+        let mut node = Box::new(StrNode {
+            s: s.into_boxed_str(),
+            next: OnceLock::new(),
+        });
+        // Append the string to the first free slot, a packet only converts a few strings
+        let mut slot = &self.head;
+        loop {
+            match slot.set(node) {
+                Ok(()) => return &slot.get().expect("set slot").s,
+                Err(n) => {
+                    node = n;
+                    slot = &slot.get().expect("set slot").next;
+                }
+            }
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &StrNode> {
+        std::iter::successors(self.head.get(), |node| node.next.get()).map(|node| node.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.get().is_none()
+    }
+}
+
+impl Drop for StrArena {
+    fn drop(&mut self) {
+        // Unlink the nodes one by one, to not recurse for long chains
+        let mut next = self.head.take();
+        while let Some(mut node) = next {
+            next = node.next.take();
+        }
+    }
+}
+
+impl std::fmt::Debug for StrArena {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StrArena").field(&self.len()).finish()
+    }
+}
+
+/// A clone starts empty, the strings are converted again when they are read
+impl Clone for StrArena {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl From<LocaleCode> for StringCodec {
+    fn from(locale: LocaleCode) -> Self {
+        match locale {
+            LocaleCode::Korea | LocaleCode::KoreaT => Self::Cp949,
+            LocaleCode::Japan => Self::ShiftJis,
+            LocaleCode::China | LocaleCode::ChinaT => Self::Gbk,
+            LocaleCode::Taiwan | LocaleCode::TaiwanT => Self::Big5,
+            LocaleCode::Global | LocaleCode::Europe | LocaleCode::RlsPe => Self::Cp1252,
+            // Unknown locales are custom clients, which are assumed to use UTF-8
+            LocaleCode::Other(_) => Self::Utf8,
+        }
+    }
+}
+
+impl StringCodec {
+    /// Decodes the string data, the data is borrowed
+    /// If no conversion is required, which is always the case for plain ASCII
+    pub fn decode<'a>(&self, data: &'a [u8]) -> NetResult<Cow<'a, str>> {
+        // Every supported code page is a superset of ASCII
+        if *self == Self::Utf8 || data.is_ascii() {
+            return Ok(Cow::Borrowed(std::str::from_utf8(data)?));
+        }
+
+        match self {
+            Self::Cp1252 => Ok(Cow::Owned(data.iter().map(|&b| cp1252_decode(b)).collect())),
+            _ => self.decode_legacy(data),
+        }
+    }
+
+    /// Length of the encoded string, fails If the string can't be encoded
+    pub fn encoded_len(&self, s: &str) -> NetResult<usize> {
+        if *self == Self::Utf8 || s.is_ascii() {
+            return Ok(s.len());
+        }
+
+        match self {
+            Self::Cp1252 => s
+                .chars()
+                .try_fold(0, |n, c| cp1252_encode(c).map(|_| n + 1))
+                .ok_or(NetError::StringCodec(*self)),
+            _ => self.encode(s).map(|data| data.len()),
+        }
+    }
+
+    /// Encodes the string, the data is borrowed
+    /// If no conversion is required, which is always the case for plain ASCII
+    pub fn encode<'a>(&self, s: &'a str) -> NetResult<Cow<'a, [u8]>> {
+        if *self == Self::Utf8 || s.is_ascii() {
+            return Ok(Cow::Borrowed(s.as_bytes()));
+        }
+
+        match self {
+            Self::Cp1252 => s
+                .chars()
+                .map(|c| cp1252_encode(c).ok_or(NetError::StringCodec(*self)))
+                .collect::<NetResult<Vec<u8>>>()
+                .map(Cow::Owned),
+            _ => self.encode_legacy(s),
+        }
+    }
+
+    /// Encoding of the legacy CJK code pages
+    #[cfg(feature = "encoding")]
+    fn legacy_encoding(&self) -> NetResult<&'static encoding_rs::Encoding> {
+        match self {
+            Self::Cp949 => Ok(encoding_rs::EUC_KR),
+            Self::ShiftJis => Ok(encoding_rs::SHIFT_JIS),
+            Self::Gbk => Ok(encoding_rs::GBK),
+            Self::Big5 => Ok(encoding_rs::BIG5),
+            Self::Utf8 | Self::Cp1252 => Err(NetError::UnsupportedStringCodec(*self)),
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    fn decode_legacy<'a>(&self, data: &'a [u8]) -> NetResult<Cow<'a, str>> {
+        let (s, had_errors) = self.legacy_encoding()?.decode_without_bom_handling(data);
+        if had_errors {
+            return Err(NetError::StringCodec(*self));
+        }
+        Ok(s)
+    }
+
+    #[cfg(feature = "encoding")]
+    fn encode_legacy<'a>(&self, s: &'a str) -> NetResult<Cow<'a, [u8]>> {
+        let (data, _, had_unmappable) = self.legacy_encoding()?.encode(s);
+        if had_unmappable {
+            return Err(NetError::StringCodec(*self));
+        }
+        Ok(data)
+    }
+
+    #[cfg(not(feature = "encoding"))]
+    fn decode_legacy<'a>(&self, _data: &'a [u8]) -> NetResult<Cow<'a, str>> {
+        Err(NetError::UnsupportedStringCodec(*self))
+    }
+
+    #[cfg(not(feature = "encoding"))]
+    fn encode_legacy<'a>(&self, _s: &'a str) -> NetResult<Cow<'a, [u8]>> {
+        Err(NetError::UnsupportedStringCodec(*self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::net::codec::handshake::LocaleCode;
+
+    use super::{StrArena, StringCodec};
+
+    #[test]
+    fn locale_codec() {
+        assert_eq!(StringCodec::from(LocaleCode::Global), StringCodec::Cp1252);
+        assert_eq!(StringCodec::from(LocaleCode::KoreaT), StringCodec::Cp949);
+        assert_eq!(StringCodec::from(LocaleCode::Japan), StringCodec::ShiftJis);
+        assert_eq!(
+            StringCodec::from(LocaleCode::Other(0x42)),
+            StringCodec::Utf8
+        );
+    }
+
+    #[test]
+    fn ascii_borrowed() {
+        for codec in [StringCodec::Utf8, StringCodec::Cp1252, StringCodec::Big5] {
+            assert!(matches!(
+                codec.decode(b"abc").unwrap(),
+                Cow::Borrowed("abc")
+            ));
+            assert!(matches!(
+                codec.encode("abc").unwrap(),
+                Cow::Borrowed(b"abc")
+            ));
+        }
+    }
+
+    #[test]
+    fn cp1252() {
+        let codec = StringCodec::Cp1252;
+        let data = codec.encode("Müller €5").unwrap();
+        assert_eq!(data.as_ref(), b"M\xFCller \x805");
+        assert_eq!(codec.decode(&data).unwrap(), "Müller €5");
+        assert_eq!(codec.encoded_len("Müller €5").unwrap(), data.len());
+
+        // Every byte decodes and encodes back to the same byte
+        let all: Vec<u8> = (0..=u8::MAX).collect();
+        let s = codec.decode(&all).unwrap();
+        assert_eq!(codec.encode(&s).unwrap().as_ref(), all.as_slice());
+
+        assert!(codec.encode("버섯").is_err());
+        assert!(codec.encoded_len("버섯").is_err());
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn cp949() {
+        let codec = StringCodec::Cp949;
+        let data = codec.encode("버섯").unwrap();
+        assert_eq!(data.len(), 4);
+        assert_eq!(codec.decode(&data).unwrap(), "버섯");
+        assert_eq!(codec.encoded_len("버섯 a").unwrap(), 6);
+        assert!(codec.encoded_len("🍄").is_err());
+    }
+
+    #[test]
+    fn str_arena() {
+        let arena = StrArena::default();
+        assert!(arena.is_empty());
+        let a = arena.alloc("a".to_string());
+        let b = arena.alloc("b".to_string());
+        assert_eq!((a, b), ("a", "b"));
+        assert_eq!(arena.len(), 2);
+    }
+}
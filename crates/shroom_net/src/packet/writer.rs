@@ -2,20 +2,19 @@ use bytes::{BufMut, BytesMut};
 
 use crate::{opcode::NetOpcode, NetError, NetResult, ShroomPacket};
 
-use super::{packet_str_len, shroom128_to_bytes};
+use super::{shroom128_to_bytes, StringCodec};
 
 /// Writer to encode a packet onto a Buffer `T`
 #[derive(Debug)]
 pub struct PacketWriter<T = BytesMut> {
     pub buf: T,
+    str_codec: StringCodec,
 }
 
 // Default implementation for `BytesMut`
 impl Default for PacketWriter<BytesMut> {
     fn default() -> Self {
-        Self {
-            buf: Default::default(),
-        }
+        Self::new(BytesMut::default())
     }
 }
 
@@ -25,6 +24,11 @@ impl<T> PacketWriter<T> {
         self.buf
     }
 
+    /// Gets the codec which is used to encode strings
+    pub fn str_codec(&self) -> StringCodec {
+        self.str_codec
+    }
+
     pub fn get_mut(&mut self) -> &mut T {
         &mut self.buf
     }
@@ -62,7 +66,12 @@ where
 {
     /// Create a new PacketWriter from any BufMut
     pub fn new(buf: T) -> Self {
-        Self { buf }
+        Self::with_str_codec(buf, StringCodec::default())
+    }
+
+    /// Create a new PacketWriter from any BufMut, which encodes strings with the given codec
+    pub fn with_str_codec(buf: T, str_codec: StringCodec) -> Self {
+        Self { buf, str_codec }
     }
 
     /// Check if n bytes still fit in the buffer
@@ -183,10 +192,10 @@ where
 
     /// Write a str
     pub fn write_str(&mut self, v: &str) -> NetResult<()> {
-        self.check_capacity(packet_str_len(v))?;
-        let b = v.as_bytes();
+        let b = self.str_codec.encode(v)?;
+        self.check_capacity(2 + b.len())?;
         self.buf.put_u16_le(b.len() as u16);
-        self.buf.put_slice(b);
+        self.buf.put_slice(&b);
         Ok(())
     }
 }
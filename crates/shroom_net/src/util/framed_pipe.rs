@@ -1,17 +1,20 @@
 use std::{ops::DerefMut, pin::Pin, sync::Arc, task::Poll};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use futures::{channel::mpsc, ready, Sink, Stream};
+use futures::{channel::mpsc, future::poll_fn, ready, Sink, Stream};
 use thiserror::Error;
+use tokio::sync::Notify;
 
 /// Tries to reserve additional memory, returns whether the additional memory
-/// was possible to claim with the maximum bounds considered
+/// was possible to claim with the maximum bounds considered.
+/// The bound is checked against the length, because the capacity of the buffer
+/// may grow beyond the maximum while the taken frames still share the allocation
 fn try_reserve_with_max_cap(buf: &mut BytesMut, additional: usize, max: usize) -> bool {
-    // TODO: makes this work properly, when bytes gets support for It
-    // Currentely It only returns false after an additional allocation and that allocation
-    // Would have to double the capacity each time
+    if buf.len() + additional > max {
+        return false;
+    }
     buf.reserve(additional);
-    buf.capacity() <= max
+    true
 }
 
 #[derive(Debug, Error)]
@@ -27,38 +30,75 @@ pub enum FramedPipeError {
     MissedFrame,
 }
 
+/// Frame on the channel, which is either on the buffer or shared
+#[derive(Debug)]
+enum PipeFrame {
+    /// Frame with the length on the buffer
+    Buf(usize),
+    /// Shared frame, which is not copied onto the buffer
+    Shared(Bytes),
+}
+
 /// A `Pipe` which works on frames
 #[derive(Debug, Clone)]
 struct FramedPipeBuf {
     buf: BytesMut,
     cap: usize,
+    /// Length of the shared frames, which count against the capacity
+    shared: usize,
     missed: usize,
 }
 
 impl FramedPipeBuf {
-    /// Create a new buffer with the given capacity
+    /// Create a new buffer with the given capacity, which is allocated once frames are sent
     fn new(cap: usize) -> Self {
         Self {
-            buf: BytesMut::with_capacity(cap),
+            buf: BytesMut::new(),
             cap,
+            shared: 0,
             missed: 0,
         }
     }
 
     /// Take a frame from the buffer
-    fn take(&mut self, n: usize) -> Result<Bytes, FramedPipeError> {
+    fn take(&mut self, frame: PipeFrame) -> Bytes {
+        match frame {
+            PipeFrame::Buf(n) => self.buf.split_to(n).freeze(),
+            PipeFrame::Shared(frame) => {
+                self.shared -= frame.len();
+                frame
+            }
+        }
+    }
+
+    /// Returns an error If frames were missed since the last check
+    fn check_missed(&mut self) -> Result<(), FramedPipeError> {
         if self.missed > 0 {
             self.missed = 0;
             return Err(FramedPipeError::MissedFrame);
         }
-
-        Ok(self.buf.split_to(n).freeze())
+        Ok(())
     }
 
     /// Checks if there's enough space on the buffer
+    fn has_space(&mut self, frame: &[u8]) -> bool {
+        try_reserve_with_max_cap(&mut self.buf, frame.len(), self.cap - self.shared)
+    }
+
+    /// Checks if there's enough space on the buffer, counts the frame as missed otherwise
     fn try_reserve(&mut self, frame: &[u8]) -> Result<(), FramedPipeError> {
         // Check if there's enough capacity
-        if !try_reserve_with_max_cap(&mut self.buf, frame.len(), self.cap) {
+        if !self.has_space(frame) {
+            self.missed += 1;
+            return Err(FramedPipeError::OutOfCapacity);
+        }
+
+        Ok(())
+    }
+
+    /// Checks if there's enough capacity for a shared frame, counts the frame as missed otherwise
+    fn try_reserve_shared(&mut self, frame: &Bytes) -> Result<(), FramedPipeError> {
+        if self.buf.len() + self.shared + frame.len() > self.cap {
             self.missed += 1;
             return Err(FramedPipeError::OutOfCapacity);
         }
@@ -78,8 +118,9 @@ type SharedFramedPipeBuf = Arc<parking_lot::Mutex<FramedPipeBuf>>;
 /// A sender for the `FramedPipe` can be cloned and used a `Sink`
 #[derive(Debug, Clone)]
 pub struct FramedPipeSender {
-    tx: mpsc::Sender<usize>,
+    tx: mpsc::Sender<PipeFrame>,
     buf: SharedFramedPipeBuf,
+    space: Arc<Notify>,
 }
 
 impl FramedPipeSender {
@@ -87,10 +128,10 @@ impl FramedPipeSender {
     fn try_push(
         frame: &[u8],
         buf: &mut FramedPipeBuf,
-        tx: &mut mpsc::Sender<usize>,
+        tx: &mut mpsc::Sender<PipeFrame>,
     ) -> Result<(), FramedPipeError> {
         buf.try_reserve(frame.as_ref())?;
-        tx.try_send(frame.as_ref().len())
+        tx.try_send(PipeFrame::Buf(frame.as_ref().len()))
             .map_err(|err| FramedPipeError::SendError(err.into_send_error()))?;
         buf.put(frame);
         Ok(())
@@ -100,7 +141,7 @@ impl FramedPipeSender {
     fn push(&mut self, frame: &[u8]) -> Result<(), FramedPipeError> {
         let mut buf = self.buf.lock();
         buf.try_reserve(frame)?;
-        self.tx.start_send(frame.len())?;
+        self.tx.start_send(PipeFrame::Buf(frame.len()))?;
         buf.put(frame);
         Ok(())
     }
@@ -111,6 +152,51 @@ impl FramedPipeSender {
         Self::try_push(item.as_ref(), buf.deref_mut(), &mut self.tx)
     }
 
+    /// Try to send shared bytes onto the pipe, the frame is passed to the receiver
+    /// without copying It onto the buffer, but still counts against the capacity
+    pub fn try_send_bytes(&mut self, item: Bytes) -> Result<(), FramedPipeError> {
+        let mut buf = self.buf.lock();
+        buf.try_reserve_shared(&item)?;
+        let len = item.len();
+        self.tx
+            .try_send(PipeFrame::Shared(item))
+            .map_err(|err| FramedPipeError::SendError(err.into_send_error()))?;
+        buf.shared += len;
+        Ok(())
+    }
+
+    /// Sends a frame onto the pipe, waits until the receiver freed enough capacity
+    /// and fails once the receiver is dropped.
+    /// The task, which drains the receiver must not await this, because the capacity
+    /// is never freed while It waits
+    pub async fn send_frame<B: AsRef<[u8]>>(&mut self, item: B) -> Result<(), FramedPipeError> {
+        let frame = item.as_ref();
+        if frame.len() > self.buf.lock().cap {
+            return Err(FramedPipeError::CapacityLimitReached);
+        }
+
+        loop {
+            // Reserves a slot on the channel for this sender, fails If the receiver was dropped
+            poll_fn(|cx| self.tx.poll_ready(cx)).await?;
+
+            // Register before checking, so no wakeup is missed
+            let space = self.space.notified();
+            // The receiver closes the channel before waking the senders on drop
+            if self.tx.is_closed() {
+                continue;
+            }
+            {
+                let mut buf = self.buf.lock();
+                if buf.has_space(frame) {
+                    self.tx.start_send(PipeFrame::Buf(frame.len()))?;
+                    buf.put(frame);
+                    return Ok(());
+                }
+            }
+            space.await;
+        }
+    }
+
     /// Try to send all frames onto a pipe
     /// May send send some frames and then cancel
     pub fn try_send_all<B: AsRef<[u8]>>(
@@ -161,11 +247,43 @@ impl<B: AsRef<[u8]>> Sink<B> for FramedPipeSender {
 /// Receiver end for the `FramedPipe`, there's at most one reader
 #[derive(Debug)]
 pub struct FramedPipeReceiver {
-    rx: mpsc::Receiver<usize>,
+    rx: mpsc::Receiver<PipeFrame>,
     buf: SharedFramedPipeBuf,
+    space: Arc<Notify>,
 }
 
-/// Stream impl for the reader, wait on the channel
+impl FramedPipeReceiver {
+    /// Takes the frame and wakes the senders, which wait for capacity
+    fn take(&self, frame: PipeFrame) -> Bytes {
+        let frame = self.buf.lock().take(frame);
+        self.space.notify_waiters();
+        frame
+    }
+
+    /// Drains up to `max` frames which are ready without waiting onto the batch,
+    /// fails with `MissedFrame` before draining If frames were missed
+    pub fn try_drain(&mut self, max: usize, batch: &mut Vec<Bytes>) -> Result<(), FramedPipeError> {
+        self.buf.lock().check_missed()?;
+        for _ in 0..max {
+            match self.rx.try_recv() {
+                Ok(frame) => batch.push(self.take(frame)),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wakes the senders, which wait for capacity, so they see the closed pipe
+impl Drop for FramedPipeReceiver {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.space.notify_waiters();
+    }
+}
+
+/// Stream impl for the reader, wait on the channel.
+/// If frames were missed `MissedFrame` is returned once before the next frame
 impl Stream for FramedPipeReceiver {
     type Item = Result<Bytes, FramedPipeError>;
 
@@ -173,10 +291,14 @@ impl Stream for FramedPipeReceiver {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        if let Err(err) = self.buf.lock().check_missed() {
+            return Poll::Ready(Some(Err(err)));
+        }
+
         // There's only one reader so we can just wait on the channel
         // and then read the frame of the buffer
         let next_frame = ready!(Pin::new(&mut self.rx).poll_next(cx));
-        Poll::Ready(next_frame.map(|frame| self.buf.lock().take(frame)))
+        Poll::Ready(next_frame.map(|frame| Ok(self.take(frame))))
     }
 }
 
@@ -185,14 +307,16 @@ impl Stream for FramedPipeReceiver {
 /// `frame_cap` describes the maximum capacity in terms of frames
 pub fn framed_pipe(buf_cap: usize, frame_cap: usize) -> (FramedPipeSender, FramedPipeReceiver) {
     let buf = Arc::new(parking_lot::Mutex::new(FramedPipeBuf::new(buf_cap)));
+    let space = Arc::new(Notify::new());
     let (tx, rx) = mpsc::channel(frame_cap);
 
     (
         FramedPipeSender {
             buf: buf.clone(),
             tx,
+            space: space.clone(),
         },
-        FramedPipeReceiver { buf, rx },
+        FramedPipeReceiver { buf, rx, space },
     )
 }
 
@@ -217,7 +341,7 @@ mod tests {
     async fn echo_pipe() {
         let (tx, mut rx) = framed_pipe(1024 * 8, 128);
 
-        const ECHO_DATA: [&'static [u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
+        const ECHO_DATA: [&[u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
 
         for _ in 0..100 {
             for data in ECHO_DATA {
@@ -231,14 +355,102 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn drain_pipe() {
+        let (mut tx, mut rx) = framed_pipe(1024 * 8, 128);
+
+        const ECHO_DATA: [&[u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
+        tx.try_send_all(ECHO_DATA.iter()).unwrap();
+
+        let mut batch = Vec::new();
+        rx.try_drain(2, &mut batch).unwrap();
+        rx.try_drain(8, &mut batch).unwrap();
+        itertools::assert_equal(batch.iter().map(|b| b.as_ref()), ECHO_DATA);
+
+        // Nothing left to drain
+        rx.try_drain(8, &mut batch).unwrap();
+        assert_eq!(batch.len(), ECHO_DATA.len());
+    }
+
+    #[tokio::test]
+    async fn shared_frames() {
+        let (mut tx, mut rx) = framed_pipe(8, 128);
+        let shared = Bytes::from_static(&[1; 4]);
+        tx.try_send_bytes(shared.clone()).unwrap();
+        tx.try_send([2; 2]).unwrap();
+        // Shared frames count against the capacity
+        assert!(tx.try_send([3; 4]).is_err());
+        assert!(tx.try_send_bytes(shared.clone()).is_err());
+
+        assert!(matches!(
+            rx.next().await.unwrap(),
+            Err(FramedPipeError::MissedFrame)
+        ));
+        let frame = rx.next().await.unwrap().unwrap();
+        assert_eq!(frame.as_ptr(), shared.as_ptr());
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [2; 2]);
+
+        // The capacity is freed again
+        tx.try_send([4; 8]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missed_frame() {
+        let (mut tx, mut rx) = framed_pipe(8, 128);
+        tx.try_send([1; 4]).unwrap();
+        assert!(tx.try_send([2; 8]).is_err());
+        tx.try_send([3; 4]).unwrap();
+
+        // The missed frame is reported once, without losing the other frames
+        assert!(matches!(
+            rx.next().await.unwrap(),
+            Err(FramedPipeError::MissedFrame)
+        ));
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [1; 4]);
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [3; 4]);
+    }
+
+    #[tokio::test]
+    async fn send_backpressure() {
+        let (mut tx, mut rx) = framed_pipe(8, 2);
+        assert!(matches!(
+            tx.send_frame([0; 9]).await,
+            Err(FramedPipeError::CapacityLimitReached)
+        ));
+
+        let sender = tokio::spawn(async move {
+            for i in 0..16u8 {
+                tx.send_frame([i; 6]).await.unwrap();
+            }
+        });
+
+        for i in 0..16u8 {
+            assert_eq!(&rx.next().await.unwrap().unwrap()[..], [i; 6]);
+        }
+        sender.await.unwrap();
+    }
 
+    #[tokio::test]
+    async fn send_closed_pipe() {
+        let (mut tx, rx) = framed_pipe(8, 2);
+        tx.send_frame([0; 6]).await.unwrap();
+
+        // The waiting sender is woken up by dropping the receiver
+        let sender = tokio::spawn(async move { tx.send_frame([1; 6]).await });
+        tokio::task::yield_now().await;
+        drop(rx);
+        assert!(matches!(
+            sender.await.unwrap(),
+            Err(FramedPipeError::SendError(_))
+        ));
+    }
 
     // Test to ensure the buffer stays at the 4096 bytes capacity
     #[tokio::test]
     async fn reclaim_echo_pipe() {
         let (tx, mut rx) = framed_pipe(1024 * 4, 128);
 
-        const ECHO_DATA: [&'static [u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
+        const ECHO_DATA: [&[u8]; 4] = [&[0xFF; 4096], &[1, 2], &[], &[0x0; 1024]];
 
         for _ in 0..100 {
             for data in ECHO_DATA {
@@ -1,6 +1,7 @@
 pub mod filetime;
 pub mod framed_pipe;
 pub mod packet_buffer;
+pub mod priority_pipe;
 
 /// Helper type to calculate size hint
 pub struct SizeHint(pub Option<usize>);
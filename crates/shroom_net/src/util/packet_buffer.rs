@@ -3,7 +3,7 @@ use std::iter;
 use bytes::BytesMut;
 use itertools::Itertools;
 
-use crate::{EncodePacket, NetResult, PacketWriter, HasOpcode};
+use crate::{packet::StringCodec, EncodePacket, NetResult, PacketWriter, HasOpcode};
 
 /// Buffer to allow to encode multiple packets onto one buffer
 /// while still allowing to iterate over the encoded packets
@@ -11,14 +11,23 @@ use crate::{EncodePacket, NetResult, PacketWriter, HasOpcode};
 pub struct PacketBuffer {
     buf: BytesMut,
     ix: Vec<usize>,
+    str_codec: StringCodec,
 }
 
 impl PacketBuffer {
+    /// Create a buffer, which encodes strings with the given codec
+    pub fn with_str_codec(str_codec: StringCodec) -> Self {
+        Self {
+            str_codec,
+            ..Default::default()
+        }
+    }
+
     /// Encode a packet onto the buffer
     pub fn encode_packet<T: EncodePacket + HasOpcode>(&mut self, pkt: T) -> NetResult<()> {
         // Store the previous index
         let ix = self.buf.len();
-        let mut pw = PacketWriter::new(&mut self.buf);
+        let mut pw = PacketWriter::with_str_codec(&mut self.buf, self.str_codec);
         
         // If an error occurs reset the index
         if let Err(err) = pw.write_opcode(T::OPCODE) {
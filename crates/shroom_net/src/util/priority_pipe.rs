@@ -0,0 +1,400 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+
+use bytes::Bytes;
+use futures::{task::AtomicWaker, Stream};
+
+use super::framed_pipe::{framed_pipe, FramedPipeError, FramedPipeReceiver, FramedPipeSender};
+
+/// Priority of a frame, lanes with a higher priority are drained first.
+/// The order is strict, so a lane which is kept busy starves the lanes with a lower priority
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    /// Critical frames like stat changes or migration
+    High,
+    #[default]
+    Normal,
+    /// Low value frames like broadcasts
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// Config for a lane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneConfig {
+    /// Max buffered bytes
+    pub buf_cap: usize,
+    /// Max buffered frames
+    pub frame_cap: usize,
+    /// A lossy lane drops the oldest frames to make room for new frames,
+    /// instead of rejecting the new frame
+    pub lossy: bool,
+}
+
+impl LaneConfig {
+    pub const fn new(buf_cap: usize, frame_cap: usize) -> Self {
+        Self {
+            buf_cap,
+            frame_cap,
+            lossy: false,
+        }
+    }
+
+    pub const fn lossy(buf_cap: usize, frame_cap: usize) -> Self {
+        Self {
+            buf_cap,
+            frame_cap,
+            lossy: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LossyBuf {
+    frames: VecDeque<Bytes>,
+    len: usize,
+    cfg: LaneConfig,
+    dropped: usize,
+}
+
+impl LossyBuf {
+    /// Pushes the frame and drops the oldest frames until It fits
+    fn push(&mut self, frame: Bytes) -> Result<(), FramedPipeError> {
+        if frame.len() > self.cfg.buf_cap || self.cfg.frame_cap == 0 {
+            return Err(FramedPipeError::CapacityLimitReached);
+        }
+
+        while self.len + frame.len() > self.cfg.buf_cap || self.frames.len() >= self.cfg.frame_cap {
+            let Some(old) = self.frames.pop_front() else {
+                break;
+            };
+            self.len -= old.len();
+            self.dropped += 1;
+        }
+
+        self.len += frame.len();
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        let frame = self.frames.pop_front()?;
+        self.len -= frame.len();
+        Some(frame)
+    }
+}
+
+#[derive(Debug)]
+struct LossyLane {
+    buf: parking_lot::Mutex<LossyBuf>,
+    waker: AtomicWaker,
+    /// Number of senders, the lane is closed once all of them are dropped
+    senders: AtomicUsize,
+}
+
+impl LossyLane {
+    fn push(&self, frame: Bytes) -> Result<(), FramedPipeError> {
+        self.buf.lock().push(frame)?;
+        self.waker.wake();
+        Ok(())
+    }
+
+    /// Pops the next frame, the buffered frames are still returned after the lane was closed
+    fn poll_pop(&self, cx: &mut std::task::Context<'_>) -> Poll<Option<Bytes>> {
+        if let Some(frame) = self.buf.lock().pop() {
+            return Poll::Ready(Some(frame));
+        }
+
+        // Register first, so a push or the last drop between the checks is not missed
+        self.waker.register(cx.waker());
+        match self.buf.lock().pop() {
+            Some(frame) => Poll::Ready(Some(frame)),
+            None if self.senders.load(Ordering::Acquire) == 0 => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Sender of a lossy lane, which closes the lane once the last sender is dropped
+#[derive(Debug)]
+struct LossySender(Arc<LossyLane>);
+
+impl Clone for LossySender {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for LossySender {
+    fn drop(&mut self) {
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum LaneSender {
+    Framed(FramedPipeSender),
+    Lossy(LossySender),
+}
+
+#[derive(Debug)]
+enum LaneReceiver {
+    Framed(FramedPipeReceiver),
+    Lossy(Arc<LossyLane>),
+}
+
+fn lane(cfg: &LaneConfig) -> (LaneSender, LaneReceiver) {
+    if cfg.lossy {
+        let lane = Arc::new(LossyLane {
+            buf: parking_lot::Mutex::new(LossyBuf {
+                frames: VecDeque::new(),
+                len: 0,
+                cfg: *cfg,
+                dropped: 0,
+            }),
+            waker: AtomicWaker::new(),
+            senders: AtomicUsize::new(1),
+        });
+        (
+            LaneSender::Lossy(LossySender(lane.clone())),
+            LaneReceiver::Lossy(lane),
+        )
+    } else {
+        let (tx, rx) = framed_pipe(cfg.buf_cap, cfg.frame_cap);
+        (LaneSender::Framed(tx), LaneReceiver::Framed(rx))
+    }
+}
+
+/// Sender for the lanes of a priority pipe, can be cloned
+#[derive(Debug, Clone)]
+pub struct PrioritySender {
+    lanes: [LaneSender; 3],
+}
+
+impl PrioritySender {
+    /// Try to send a frame onto the lane of the priority,
+    /// lossy lanes drop the oldest frames instead of failing
+    pub fn try_send<B: AsRef<[u8]>>(
+        &mut self,
+        prio: Priority,
+        item: B,
+    ) -> Result<(), FramedPipeError> {
+        match &mut self.lanes[prio.lane()] {
+            LaneSender::Framed(tx) => tx.try_send(item),
+            LaneSender::Lossy(lane) => lane.0.push(Bytes::copy_from_slice(item.as_ref())),
+        }
+    }
+
+    /// Try to send shared bytes onto the lane of the priority, without copying them
+    pub fn try_send_bytes(&mut self, prio: Priority, item: Bytes) -> Result<(), FramedPipeError> {
+        match &mut self.lanes[prio.lane()] {
+            LaneSender::Framed(tx) => tx.try_send_bytes(item),
+            LaneSender::Lossy(lane) => lane.0.push(item),
+        }
+    }
+
+    /// Try to send all frames onto the lane of the priority
+    /// May send send some frames and then cancel
+    pub fn try_send_all<B: AsRef<[u8]>>(
+        &mut self,
+        prio: Priority,
+        items: impl Iterator<Item = B>,
+    ) -> Result<(), FramedPipeError> {
+        match &mut self.lanes[prio.lane()] {
+            LaneSender::Framed(tx) => tx.try_send_all(items),
+            LaneSender::Lossy(lane) => items
+                .into_iter()
+                .try_for_each(|i| lane.0.push(Bytes::copy_from_slice(i.as_ref()))),
+        }
+    }
+
+    /// Sends a frame onto the lane of the priority, waits for capacity on lanes which are not lossy
+    pub async fn send_frame<B: AsRef<[u8]>>(
+        &mut self,
+        prio: Priority,
+        item: B,
+    ) -> Result<(), FramedPipeError> {
+        match &mut self.lanes[prio.lane()] {
+            LaneSender::Framed(tx) => tx.send_frame(item).await,
+            LaneSender::Lossy(lane) => lane.0.push(Bytes::copy_from_slice(item.as_ref())),
+        }
+    }
+}
+
+/// Receiver for the lanes of a priority pipe, which yields the frames in priority order
+#[derive(Debug)]
+pub struct PriorityReceiver {
+    lanes: [LaneReceiver; 3],
+}
+
+impl PriorityReceiver {
+    /// Number of frames, which were dropped by lossy lanes
+    pub fn dropped(&self) -> usize {
+        self.lanes
+            .iter()
+            .map(|lane| match lane {
+                LaneReceiver::Lossy(lane) => lane.buf.lock().dropped,
+                LaneReceiver::Framed(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Drains up to `max` frames which are ready without waiting onto the batch,
+    /// starting with the lane with the highest priority
+    pub fn try_drain(&mut self, max: usize, batch: &mut Vec<Bytes>) -> Result<(), FramedPipeError> {
+        let limit = batch.len() + max;
+        for lane in self.lanes.iter_mut() {
+            let remaining = limit - batch.len();
+            match lane {
+                LaneReceiver::Framed(rx) => rx.try_drain(remaining, batch)?,
+                LaneReceiver::Lossy(lane) => {
+                    let mut buf = lane.buf.lock();
+                    for _ in 0..remaining {
+                        let Some(frame) = buf.pop() else {
+                            break;
+                        };
+                        batch.push(frame);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Yields the next frame of the lane with the highest priority, which has a frame
+impl Stream for PriorityReceiver {
+    type Item = Result<Bytes, FramedPipeError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let mut closed = 0;
+        for lane in self.lanes.iter_mut() {
+            match lane {
+                LaneReceiver::Framed(rx) => match Pin::new(rx).poll_next(cx) {
+                    Poll::Ready(Some(frame)) => return Poll::Ready(Some(frame)),
+                    Poll::Ready(None) => closed += 1,
+                    Poll::Pending => {}
+                },
+                LaneReceiver::Lossy(lane) => match lane.poll_pop(cx) {
+                    Poll::Ready(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                    Poll::Ready(None) => closed += 1,
+                    Poll::Pending => {}
+                },
+            }
+        }
+
+        // Ends once every lane is closed and drained
+        if closed == self.lanes.len() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+/// Create a priority pipe with the config for each lane, ordered by `Priority`
+pub fn priority_pipe(lanes: &[LaneConfig; 3]) -> (PrioritySender, PriorityReceiver) {
+    let [high, normal, low] = lanes.each_ref().map(lane);
+    (
+        PrioritySender {
+            lanes: [high.0, normal.0, low.0],
+        },
+        PriorityReceiver {
+            lanes: [high.1, normal.1, low.1],
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    const LANES: [LaneConfig; 3] = [
+        LaneConfig::new(64, 8),
+        LaneConfig::new(64, 8),
+        LaneConfig::lossy(16, 3),
+    ];
+
+    #[tokio::test]
+    async fn priority_order() {
+        let (mut tx, mut rx) = priority_pipe(&LANES);
+        tx.try_send(Priority::Low, [3]).unwrap();
+        tx.try_send(Priority::Normal, [2]).unwrap();
+        tx.try_send(Priority::High, [1]).unwrap();
+        tx.send_frame(Priority::High, [1, 1]).await.unwrap();
+
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [1]);
+        let mut batch = Vec::new();
+        rx.try_drain(8, &mut batch).unwrap();
+        itertools::assert_equal(batch.iter().map(|b| b.as_ref()), [&[1, 1][..], &[2], &[3]]);
+    }
+
+    #[tokio::test]
+    async fn lossy_lane() {
+        let (mut tx, mut rx) = priority_pipe(&LANES);
+        assert!(tx.try_send(Priority::Low, [0; 17]).is_err());
+
+        // Frame cap drops the oldest frame
+        for i in 0..4u8 {
+            tx.try_send(Priority::Low, [i]).unwrap();
+        }
+        // Byte cap drops the next frame
+        tx.try_send(Priority::Low, [4; 14]).unwrap();
+        assert_eq!(rx.dropped(), 2);
+
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [2]);
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [3]);
+        assert_eq!(&rx.next().await.unwrap().unwrap()[..], [4; 14]);
+
+        // Wakes the receiver
+        let recv = tokio::spawn(async move { rx.next().await.unwrap().unwrap() });
+        tokio::task::yield_now().await;
+        tx.try_send(Priority::Low, [5]).unwrap();
+        assert_eq!(&recv.await.unwrap()[..], [5]);
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (mut tx, mut rx) = priority_pipe(&LANES);
+        let mut tx2 = tx.clone();
+        tx.try_send(Priority::Low, [1]).unwrap();
+        drop(tx);
+        tx2.try_send(Priority::Normal, [2]).unwrap();
+
+        // Buffered frames are still received, after all senders are dropped
+        let recv = tokio::spawn(async move {
+            let mut frames = Vec::new();
+            while let Some(frame) = rx.next().await {
+                frames.push(frame.unwrap());
+            }
+            frames
+        });
+        tokio::task::yield_now().await;
+        drop(tx2);
+        itertools::assert_equal(
+            recv.await.unwrap().iter().map(|b| b.as_ref()),
+            [&[2][..], &[1]],
+        );
+    }
+}
@@ -62,6 +62,15 @@ impl PacketField {
         }
     }
 
+    /// Get the packet_len_with expr for this field, conditional fields use the UTF-8 length
+    pub fn packet_len_with_expr(&self, field_name: &TokenStream) -> TokenStream {
+        if self.get_cond().is_some() {
+            self.packet_len_expr(field_name)
+        } else {
+            quote::quote! ( self.#field_name.packet_len_with(str_codec)? )
+        }
+    }
+
     /// Get the size_hint expr for this field
     pub fn size_hint_expr(&self) -> TokenStream {
         let ty = &self.ty;
@@ -200,6 +209,10 @@ impl ShroomPacket {
             let len = field.packet_len_expr(&field_name);
             quote::quote!( + #len )
         });
+        let struct_packet_len_with_fields = self.fields_with_name().map(|((_, field_name), field)| {
+            let len = field.packet_len_with_expr(&field_name);
+            quote::quote!( + #len )
+        });
 
         // Generate EncodePacket
         token_stream.extend(quote::quote!(impl #impl_generics shroom_net::EncodePacket for #struct_name #ty_generics #where_clause {
@@ -213,6 +226,11 @@ impl ShroomPacket {
             fn packet_len(&self) -> usize {
                 0 #(#struct_packet_len_fields)*
             }
+
+            #[allow(unused_variables)]
+            fn packet_len_with(&self, str_codec: shroom_net::packet::StringCodec) -> shroom_net::NetResult<usize> {
+                Ok(0 #(#struct_packet_len_with_fields)*)
+            }
         }));
         Ok(())
     }